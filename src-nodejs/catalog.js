@@ -36,7 +36,8 @@ module.exports = class IndexCatalog extends EventEmitter {
     }
     let method = 'create_index'
     if (opts.ram) method = 'create_ram_index'
-    await this.pipe.request(method, { name, schema })
+    const { options } = opts
+    await this.pipe.request(method, { name, schema, options })
     return new Index(this, name)
   }
   async delete (name) {
@@ -60,8 +61,31 @@ module.exports = class IndexCatalog extends EventEmitter {
     return this.pipe.request('index_exists', name)
   }
 
-  multiQuery (query, indexes) {
-    return this.pipe.request('query_multi', { indexes, query })
+  async list () {
+    return this.pipe.request('list_indexes', {})
+  }
+
+  multiQuery (query, indexes, opts = {}) {
+    const { limit, offset, cursors, fields, defaultFields, boosts, conjunction, lenient, mode, editDistance, sort, filter, merge, strict, timeout, signal } = opts
+    return this.pipe.request('query_multi', {
+      indexes,
+      query,
+      limit,
+      offset,
+      cursors,
+      fields,
+      default_fields: defaultFields,
+      boosts,
+      conjunction,
+      lenient,
+      mode,
+      edit_distance: editDistance,
+      sort,
+      filter,
+      merge,
+      strict,
+      timeout_ms: timeout
+    }, { signal })
   }
 
   readMeta (name) {
@@ -94,6 +118,14 @@ class Index {
     return this.catalog.readMeta(this.name)
   }
 
+  async schema () {
+    return this.request('get_schema', this.name)
+  }
+
+  async setOptions (options) {
+    return this.request('set_index_options', { index: this.name, options })
+  }
+
   async segmentInfo () {
     const meta = await this.meta()
     return meta.segments
@@ -104,23 +136,81 @@ class Index {
   }
 
   async query (query, opts = {}) {
-    const { limit, snippetField } = opts
-    return this.request('query', { index: this.name, query, limit, snippet_field: snippetField })
+    const { limit, offset, cursor, fields, snippetField, snippetFields, snippetMaxChars, snippetFormat, snippetTags, defaultFields, boosts, conjunction, lenient, mode, editDistance, sort, filter, timeout, signal } = opts
+    return this.request('query', {
+      index: this.name,
+      query,
+      limit,
+      offset,
+      cursor,
+      fields,
+      snippet_field: snippetField,
+      snippet_fields: snippetFields,
+      snippet_max_chars: snippetMaxChars,
+      snippet_format: snippetFormat,
+      snippet_tags: snippetTags,
+      default_fields: defaultFields,
+      boosts,
+      conjunction,
+      lenient,
+      mode,
+      edit_distance: editDistance,
+      sort,
+      filter,
+      timeout_ms: timeout
+    }, { signal })
   }
 
   async queryJson (search, opts = {}) {
-    const response = await this.request('query_json', { index: this.name, search })
+    const { offset, cursor, fields, highlight, facets, aggregations, sort, filter, timeout, signal } = opts
+    const response = await this.request('query_json', {
+      index: this.name,
+      search,
+      offset,
+      cursor,
+      fields,
+      highlight,
+      facets,
+      aggregations,
+      sort,
+      filter,
+      timeout_ms: timeout
+    }, { signal })
     // TODO: Why is this needed??
     return JSON.parse(response)
   }
 
-  async add (docs) {
-    return this.addDocuments(docs)
+  async add (docs, opts) {
+    return this.addDocuments(docs, opts)
+  }
+
+  async addDocuments (documents, opts = {}) {
+    const { commit, onError } = opts
+    const result = await this.request('add_documents', { index: this.name, documents, commit, on_error: onError })
+    return rejectInvalid(result, onError)
+  }
+
+  async commit (payload) {
+    return this.request('commit', { index: this.name, payload })
   }
 
-  async addDocuments (documents) {
-    documents = transformDocs(documents)
-    return this.request('add_documents', { index: this.name, documents })
+  async prepareCommit () {
+    return this.request('prepare_commit', this.name)
+  }
+
+  async rollback () {
+    return this.request('rollback', this.name)
+  }
+
+  async deleteDocuments (field, values, opts = {}) {
+    const { commit } = opts
+    return this.request('delete_documents', { index: this.name, field, values, commit })
+  }
+
+  async updateDocuments (field, documents, opts = {}) {
+    const { commit, onError } = opts
+    const result = await this.request('update_documents', { index: this.name, field, documents, commit, on_error: onError })
+    return rejectInvalid(result, onError)
   }
 
   async addSegments (segments) {
@@ -128,17 +218,11 @@ class Index {
   }
 }
 
-function transformDocs (documents) {
-  documents = documents.map(doc => {
-    let tuples = []
-    for (let [field, value] of Object.entries(doc)) {
-      if (Array.isArray(value)) {
-        value.forEach(val => tuples.push([field, val]))
-      } else {
-        tuples.push([field, value])
-      }
-    }
-    return tuples
-  })
-  return documents
+// Unless invalid documents are skipped, they fail the request.
+function rejectInvalid (result, onError) {
+  if (onError === 'skip' || !result.errors.length) return result
+  const error = new Error('Invalid documents.')
+  error.code = 'invalid_argument'
+  error.details = result.errors
+  throw error
 }
@@ -29,11 +29,17 @@ function getCommandAndArgs () {
 
 function openSonar (path, opts = {}) {
   path = p.resolve(path)
-  const [command, args] = getCommandAndArgs()
-  args.push(path)
-  const pipe = new Pipe(command, args, {
+  const pipeOpts = {
     log: opts.log || (process.env.RUST_ENV === 'development' && console.log)
-  })
+  }
+  let pipe
+  if (opts.connect) {
+    pipe = Pipe.connect(opts.connect, pipeOpts)
+  } else {
+    const [command, args] = getCommandAndArgs()
+    args.push(path)
+    pipe = new Pipe(command, args, pipeOpts)
+  }
   opts.path = path
   const catalog = new Sonar(pipe, opts)
   return catalog
@@ -1,4 +1,5 @@
 const { spawn } = require('child_process')
+const net = require('net')
 const pump = require('pump')
 const Duplexify = require('duplexify')
 const c = require('ansi-colors')
@@ -8,6 +9,7 @@ const split2 = require('split2')
 const debug = require('debug')('sonar-tantivy')
 
 module.exports = commandPipe
+module.exports.connect = connectPipe
 
 const methods = Symbol('methods')
 const callbacks = Symbol('callbacks')
@@ -54,6 +56,32 @@ function commandPipe (command, args = [], opts = {}) {
   return rpcStream
 }
 
+// Connect to a sonar-tantivy process that was started with --listen.
+function connectPipe (address, opts = {}) {
+  debug(`Connect: ${address}`)
+  const socket = isUnixAddress(address)
+    ? net.connect({ path: address })
+    : net.connect(parseTcpAddress(address))
+
+  const rpcStream = new RpcPipe(opts)
+  pump(socket, rpcStream, socket, err => {
+    if (err) rpcStream.emit('error', err)
+    rpcStream.destroy()
+  })
+  rpcStream.socket = socket
+
+  return rpcStream
+}
+
+function isUnixAddress (address) {
+  return address.includes('/') || address.endsWith('.sock')
+}
+
+function parseTcpAddress (address) {
+  const idx = address.lastIndexOf(':')
+  return { host: address.substring(0, idx) || 'localhost', port: Number(address.substring(idx + 1)) }
+}
+
 class RpcPipe extends Duplexify {
   constructor (opts = {}) {
     super()
@@ -96,21 +124,41 @@ class RpcPipe extends Duplexify {
     this[methods][method] = cb
   }
 
-  request (method, msg, cb) {
+  request (method, msg, opts, cb) {
+    if (typeof opts === 'function') {
+      cb = opts
+      opts = {}
+    }
+    opts = opts || {}
     if (!cb) {
       return new Promise((resolve, reject) => {
         cb = (err, data) => err ? reject(err) : resolve(data)
-        this._sendRequest(method, msg, cb)
+        this._sendRequest(method, msg, opts, cb)
       })
     } else {
-      this._sendRequest(method, msg, cb)
+      this._sendRequest(method, msg, opts, cb)
     }
   }
 
-  _sendRequest (method, msg, cb) {
+  cancel (id, cb) {
+    return this.request('cancel', id, cb)
+  }
+
+  _sendRequest (method, msg, opts, cb) {
     const id = ++this[counter]
     if (cb) this[callbacks][id * -1] = cb
 
+    const { signal } = opts
+    if (signal) {
+      const onabort = () => this.cancel(id).catch(() => {})
+      if (signal.aborted) return cb(abortError())
+      signal.addEventListener('abort', onabort, { once: true })
+      this[callbacks][id * -1] = (err, data) => {
+        signal.removeEventListener('abort', onabort)
+        cb(err, data)
+      }
+    }
+
     const message = { id, method, msg }
 
     this.out.write(message)
@@ -151,12 +199,26 @@ class RpcPipe extends Duplexify {
     if (!this[callbacks][id]) {
       this.emit('error', new Error('No callback for message: ' + JSON.stringify(message)))
     }
-    this[callbacks][id](err, msg)
+    this[callbacks][id](err && toError(err), msg)
     // Todo: Keep the callbacks to allow streaming?
     delete this[callbacks][id]
   }
 }
 
+function toError (err) {
+  if (typeof err !== 'object') return new Error(err)
+  const error = new Error(err.message)
+  error.code = err.code
+  if (err.details) error.details = err.details
+  return error
+}
+
+function abortError () {
+  const error = new Error('Request was cancelled.')
+  error.code = 'cancelled'
+  return error
+}
+
 function logStream (log, stream, name) {
   if (log === true) log = debug
   name = name ? name + ':' : ''
@@ -0,0 +1,774 @@
+use crate::filter::Filter;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use tantivy::aggregation::agg_req::{
+    Aggregation as TantivyRequest, BucketAggregation, BucketAggregationType, MetricAggregation,
+};
+use tantivy::aggregation::agg_result::{
+    AggregationResult as TantivyResult, BucketResult, MetricResult,
+};
+use tantivy::aggregation::bucket::{
+    HistogramAggregation, RangeAggregation, RangeAggregationRange, TermsAggregation,
+};
+use tantivy::aggregation::intermediate_agg_result::IntermediateAggregationResults;
+use tantivy::aggregation::metric::{AverageAggregation, StatsAggregation};
+use tantivy::aggregation::{
+    AggregationCollector as TantivyCollector,
+    AggregationSegmentCollector as TantivySegmentCollector, Key,
+};
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::fastfield::{DynamicFastFieldReader, FastFieldReader, FastValue};
+use tantivy::query::{Scorer, Weight};
+use tantivy::schema::{Field, FieldType, Schema};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentOrdinal, SegmentReader};
+use toshi_types::Error;
+
+/// Aggregations to compute over the hits of a query, by name.
+pub type Aggregations = BTreeMap<String, AggregationRequest>;
+
+/// An aggregation over the values of a fast field, in the format of Elasticsearch.
+///
+/// Hits without a value in the field are not aggregated. Dates are aggregated as unix
+/// timestamps in milliseconds, as they are added to documents.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregationRequest {
+    /// Count the hits for the most frequent values (default 10 values).
+    Terms {
+        field: String,
+        size: Option<usize>,
+    },
+    /// Count the hits in ranges of values. The ranges must not overlap.
+    Range {
+        field: String,
+        ranges: Vec<RangeRequest>,
+    },
+    /// Count the hits in buckets of a fixed size.
+    Histogram {
+        field: String,
+        interval: f64,
+    },
+    /// Count the hits in buckets of a fixed duration, e.g. `1d` (units `s`, `m`, `h`, `d`, `w`).
+    DateHistogram {
+        field: String,
+        interval: String,
+    },
+    Min {
+        field: String,
+    },
+    Max {
+        field: String,
+    },
+    Avg {
+        field: String,
+    },
+    Sum {
+        field: String,
+    },
+    /// Count, min, max, avg and sum.
+    Stats {
+        field: String,
+    },
+}
+
+/// A range of values. `from` is inclusive, `to` exclusive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RangeRequest {
+    pub key: Option<String>,
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+}
+
+/// The result of an aggregation, as sent to clients.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum AggregationResult {
+    Buckets { buckets: Vec<Bucket> },
+    Value { value: Option<f64> },
+    Stats(StatsResult),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Bucket {
+    /// The value of a term, the key of a range or the start of a histogram bucket.
+    pub key: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<f64>,
+    pub doc_count: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StatsResult {
+    pub count: u64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    pub sum: f64,
+}
+
+/// The type of the values of a fast field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ValueType {
+    U64,
+    I64,
+    F64,
+    Date,
+    Str,
+}
+
+impl ValueType {
+    /// Decode the u64 representation of a fast field value. Dates are decoded to milliseconds.
+    fn decode(&self, value: u64) -> f64 {
+        match self {
+            ValueType::U64 | ValueType::Str => value as f64,
+            ValueType::I64 => i64::from_u64(value) as f64,
+            ValueType::Date => date_millis(value) as f64,
+            ValueType::F64 => f64::from_u64(value),
+        }
+    }
+
+    fn to_json(self, value: u64) -> serde_json::Value {
+        match self {
+            ValueType::U64 | ValueType::Str => value.into(),
+            ValueType::I64 => i64::from_u64(value).into(),
+            ValueType::Date => date_millis(value).into(),
+            ValueType::F64 => f64::from_u64(value).into(),
+        }
+    }
+
+    /// Convert a bound of a range to the value of a fast field and back, as tantivy does, to
+    /// find the bucket of the range in the results of tantivy. `None` is an open bound.
+    fn bound(&self, bound: Option<f64>, open: u64) -> Option<f64> {
+        let value = match self {
+            ValueType::U64 => bound? as u64,
+            ValueType::I64 => (bound? as i64).to_u64(),
+            _ => bound?.to_u64(),
+        };
+        if value == open {
+            None
+        } else {
+            Some(self.decode(value))
+        }
+    }
+}
+
+/// Date fast fields have the unix timestamp in seconds.
+fn date_millis(value: u64) -> i64 {
+    i64::from_u64(value).saturating_mul(1000)
+}
+
+/// How an aggregation is computed.
+enum AggregationKind {
+    /// With the aggregations of tantivy, for numeric fields and terms of text fields.
+    Tantivy(TantivyCollector),
+    /// Count the hits for each value, for terms of numeric and date fields.
+    Terms(usize),
+    /// Count the hits in buckets of a number of milliseconds, for date fields.
+    DateHistogram(i64),
+    /// Ranges and metrics of date fields, from the counts of their values in milliseconds.
+    /// tantivy's aggregations would see the timestamps in seconds.
+    DateValues,
+}
+
+/// An aggregation, checked against the schema.
+struct Aggregation {
+    name: String,
+    request: AggregationRequest,
+    value_type: ValueType,
+    field: Field,
+    kind: AggregationKind,
+    /// The documents with a value in the field. Fast fields have a default value for
+    /// documents without a value, so only these documents are aggregated.
+    exists: Box<dyn Weight>,
+}
+
+/// A collector that computes aggregations over the values of fast fields.
+///
+/// The aggregations that tantivy supports are computed by tantivy, the others (terms of
+/// numbers and dates, date histograms, and ranges and metrics of dates) by counting the values
+/// of the hits.
+pub struct AggregationCollector {
+    aggregations: Vec<Aggregation>,
+}
+
+impl AggregationCollector {
+    pub fn new(searcher: &Searcher, requests: &Aggregations) -> Result<Self, Error> {
+        let schema = searcher.schema();
+        let mut aggregations = vec![];
+        for (name, request) in requests {
+            let (field_name, value_type, kind) = aggregation_kind(schema, name, request)?;
+            let field = schema.get_field(field_name).unwrap();
+            let exists = Filter::Exists {
+                field: field_name.to_string(),
+            }
+            .to_query(schema)?
+            .weight(searcher, false)?;
+            aggregations.push(Aggregation {
+                name: name.clone(),
+                request: request.clone(),
+                value_type,
+                field,
+                kind,
+                exists,
+            });
+        }
+        Ok(AggregationCollector { aggregations })
+    }
+}
+
+fn aggregation_kind<'a>(
+    schema: &Schema,
+    name: &str,
+    request: &'a AggregationRequest,
+) -> Result<(&'a str, ValueType, AggregationKind), Error> {
+    let invalid = |message: &str| Err(Error::QueryError(format!("{}: {}", message, name)));
+    let (field, value_type) = match request {
+        AggregationRequest::Terms { field, .. }
+        | AggregationRequest::Range { field, .. }
+        | AggregationRequest::Histogram { field, .. }
+        | AggregationRequest::DateHistogram { field, .. }
+        | AggregationRequest::Min { field }
+        | AggregationRequest::Max { field }
+        | AggregationRequest::Avg { field }
+        | AggregationRequest::Sum { field }
+        | AggregationRequest::Stats { field } => (field, fast_field(schema, field)?),
+    };
+    let bucket = |bucket_agg| {
+        TantivyRequest::Bucket(BucketAggregation {
+            bucket_agg,
+            sub_aggregation: Default::default(),
+        })
+    };
+    let tantivy_request = match (request, value_type) {
+        (AggregationRequest::Terms { size, .. }, ValueType::Str) => {
+            bucket(BucketAggregationType::Terms(TermsAggregation {
+                field: field.clone(),
+                size: Some(size.unwrap_or(10) as u32),
+                ..Default::default()
+            }))
+        }
+        (AggregationRequest::Terms { size, .. }, _) => {
+            let kind = AggregationKind::Terms(size.unwrap_or(10));
+            return Ok((field, value_type, kind));
+        }
+        (_, ValueType::Str) => return invalid("Only terms can be aggregated for text fields"),
+        (AggregationRequest::DateHistogram { interval, .. }, ValueType::Date) => {
+            let millis = parse_interval(interval)
+                .ok_or_else(|| Error::QueryError(format!("Invalid interval: {}", interval)))?;
+            return Ok((field, value_type, AggregationKind::DateHistogram(millis)));
+        }
+        (AggregationRequest::DateHistogram { .. }, _) => {
+            return invalid("A date histogram needs a date field")
+        }
+        (AggregationRequest::Histogram { .. }, ValueType::Date) => {
+            return invalid("Date fields need a date histogram")
+        }
+        (AggregationRequest::Range { ranges, .. }, _) if ranges.is_empty() => {
+            return invalid("No ranges in aggregation")
+        }
+        (_, ValueType::Date) => return Ok((field, value_type, AggregationKind::DateValues)),
+        (AggregationRequest::Range { ranges, .. }, _) => {
+            let ranges = ranges
+                .iter()
+                .map(|range| RangeAggregationRange {
+                    from: range.from,
+                    to: range.to,
+                })
+                .collect();
+            bucket(BucketAggregationType::Range(RangeAggregation {
+                field: field.clone(),
+                ranges,
+            }))
+        }
+        (AggregationRequest::Histogram { interval, .. }, _) => {
+            if interval.is_nan() || *interval <= 0.0 {
+                return invalid("The interval has to be positive in aggregation");
+            }
+            bucket(BucketAggregationType::Histogram(HistogramAggregation {
+                field: field.clone(),
+                interval: *interval,
+                // Buckets without hits are left out.
+                min_doc_count: Some(1),
+                ..Default::default()
+            }))
+        }
+        (AggregationRequest::Avg { .. }, _) => {
+            TantivyRequest::Metric(MetricAggregation::Average(AverageAggregation {
+                field: field.clone(),
+            }))
+        }
+        // Min, max and sum are part of the stats in tantivy.
+        _ => TantivyRequest::Metric(MetricAggregation::Stats(StatsAggregation {
+            field: field.clone(),
+        })),
+    };
+    let mut tantivy_requests = HashMap::new();
+    tantivy_requests.insert(name.to_string(), tantivy_request);
+    let kind = AggregationKind::Tantivy(TantivyCollector::from_aggs(tantivy_requests));
+    Ok((field, value_type, kind))
+}
+
+fn fast_field(schema: &Schema, field_name: &str) -> Result<ValueType, Error> {
+    let field = schema
+        .get_field(field_name)
+        .ok_or_else(|| Error::QueryError(format!("Field not found: {}", field_name)))?;
+    let entry = schema.get_field_entry(field);
+    let value_type = match entry.field_type() {
+        FieldType::U64(_) => ValueType::U64,
+        FieldType::I64(_) => ValueType::I64,
+        FieldType::F64(_) => ValueType::F64,
+        FieldType::Date(_) => ValueType::Date,
+        FieldType::Str(_) => ValueType::Str,
+        _ => {
+            let message = format!("Not a numeric, date or text field: {}", field_name);
+            return Err(Error::QueryError(message));
+        }
+    };
+    // Hits without a value are found in the index.
+    if !entry.is_fast() || !entry.is_indexed() {
+        return Err(Error::QueryError(format!(
+            "Not an indexed fast field: {}",
+            field_name
+        )));
+    }
+    Ok(value_type)
+}
+
+/// Parse an interval like `1d` into milliseconds.
+fn parse_interval(interval: &str) -> Option<i64> {
+    let unit_start = interval.find(|c: char| !c.is_ascii_digit())?;
+    let count: i64 = interval[..unit_start].parse().ok()?;
+    let unit = match &interval[unit_start..] {
+        "s" => 1000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        "d" => 86_400_000,
+        "w" => 604_800_000,
+        _ => return None,
+    };
+    count.checked_mul(unit).filter(|millis| *millis > 0)
+}
+
+/// The aggregation results of a search, by name.
+pub struct AggregationFruit(BTreeMap<String, AggregationResult>);
+
+impl AggregationFruit {
+    pub fn results(self) -> BTreeMap<String, AggregationResult> {
+        self.0
+    }
+}
+
+/// The result of an aggregation that tantivy computed, in the format of the request.
+fn tantivy_result(aggregation: &Aggregation, result: TantivyResult) -> AggregationResult {
+    let value_type = aggregation.value_type;
+    let buckets = |buckets: Vec<Bucket>| AggregationResult::Buckets { buckets };
+    match (&aggregation.request, result) {
+        (
+            AggregationRequest::Range { ranges, .. },
+            TantivyResult::BucketResult(BucketResult::Range { buckets: entries }),
+        ) => buckets(
+            ranges
+                .iter()
+                .map(|range| {
+                    // tantivy adds buckets for the values between the ranges.
+                    let from = value_type.bound(range.from, u64::MIN);
+                    let to = value_type.bound(range.to, u64::MAX);
+                    let doc_count = entries
+                        .iter()
+                        .find(|entry| entry.from == from && entry.to == to)
+                        .map_or(0, |entry| entry.doc_count);
+                    Bucket {
+                        key: range_key(range).into(),
+                        from: range.from,
+                        to: range.to,
+                        doc_count,
+                    }
+                })
+                .collect(),
+        ),
+        (
+            _,
+            TantivyResult::BucketResult(BucketResult::Histogram { buckets: entries })
+            | TantivyResult::BucketResult(BucketResult::Terms {
+                buckets: entries, ..
+            }),
+        ) => buckets(
+            entries
+                .into_iter()
+                .map(|entry| Bucket {
+                    key: match entry.key {
+                        Key::Str(key) => key.into(),
+                        Key::F64(key) if value_type == ValueType::F64 => key.into(),
+                        Key::F64(key) => (key as i64).into(),
+                    },
+                    from: None,
+                    to: None,
+                    doc_count: entry.doc_count,
+                })
+                .collect(),
+        ),
+        (_, TantivyResult::MetricResult(MetricResult::Average(average))) => {
+            AggregationResult::Value {
+                value: average.value,
+            }
+        }
+        (request, TantivyResult::MetricResult(MetricResult::Stats(stats))) => match request {
+            AggregationRequest::Min { .. } => AggregationResult::Value { value: stats.min },
+            AggregationRequest::Max { .. } => AggregationResult::Value { value: stats.max },
+            AggregationRequest::Sum { .. } => AggregationResult::Value {
+                value: Some(stats.sum),
+            },
+            _ => AggregationResult::Stats(StatsResult {
+                count: stats.count as u64,
+                min: stats.min,
+                max: stats.max,
+                avg: stats.avg,
+                sum: stats.sum,
+            }),
+        },
+        _ => AggregationResult::Value { value: None },
+    }
+}
+
+/// The result of an aggregation from the counts of the values of the hits.
+fn counted_result(aggregation: &Aggregation, counts: HashMap<u64, u64>) -> AggregationResult {
+    let value_type = aggregation.value_type;
+    let buckets = match aggregation.kind {
+        AggregationKind::Terms(size) => {
+            let mut counts: Vec<(u64, u64)> = counts.into_iter().collect();
+            // Most frequent first, equal counts by value.
+            counts.sort_by(|(a_key, a_count), (b_key, b_count)| {
+                b_count.cmp(a_count).then_with(|| {
+                    value_type
+                        .decode(*a_key)
+                        .partial_cmp(&value_type.decode(*b_key))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+            });
+            counts
+                .into_iter()
+                .take(size)
+                .map(|(key, doc_count)| Bucket {
+                    key: value_type.to_json(key),
+                    from: None,
+                    to: None,
+                    doc_count,
+                })
+                .collect()
+        }
+        AggregationKind::DateHistogram(interval) => {
+            let mut buckets: BTreeMap<i64, u64> = BTreeMap::new();
+            for (value, count) in counts {
+                let millis = date_millis(value);
+                *buckets
+                    .entry(millis - millis.rem_euclid(interval))
+                    .or_insert(0) += count;
+            }
+            buckets
+                .into_iter()
+                .map(|(key, doc_count)| Bucket {
+                    key: key.into(),
+                    from: None,
+                    to: None,
+                    doc_count,
+                })
+                .collect()
+        }
+        AggregationKind::DateValues => return date_result(&aggregation.request, counts),
+        AggregationKind::Tantivy(_) => vec![],
+    };
+    AggregationResult::Buckets { buckets }
+}
+
+/// The result of a range or metric aggregation of a date field, from the counts of its values.
+fn date_result(request: &AggregationRequest, counts: HashMap<u64, u64>) -> AggregationResult {
+    let values: Vec<(f64, u64)> = counts
+        .into_iter()
+        .map(|(value, count)| (date_millis(value) as f64, count))
+        .collect();
+    if let AggregationRequest::Range { ranges, .. } = request {
+        let buckets = ranges
+            .iter()
+            .map(|range| {
+                let in_range = |value: f64| {
+                    !matches!(range.from, Some(from) if value < from)
+                        && !matches!(range.to, Some(to) if value >= to)
+                };
+                Bucket {
+                    key: range_key(range).into(),
+                    from: range.from,
+                    to: range.to,
+                    doc_count: values
+                        .iter()
+                        .filter(|(value, _)| in_range(*value))
+                        .map(|(_, count)| count)
+                        .sum(),
+                }
+            })
+            .collect();
+        return AggregationResult::Buckets { buckets };
+    }
+    let count: u64 = values.iter().map(|(_, count)| count).sum();
+    let sum: f64 = values
+        .iter()
+        .map(|(value, count)| value * *count as f64)
+        .sum();
+    let min = values.iter().map(|(value, _)| *value).reduce(f64::min);
+    let max = values.iter().map(|(value, _)| *value).reduce(f64::max);
+    let avg = if count > 0 {
+        Some(sum / count as f64)
+    } else {
+        None
+    };
+    match request {
+        AggregationRequest::Min { .. } => AggregationResult::Value { value: min },
+        AggregationRequest::Max { .. } => AggregationResult::Value { value: max },
+        AggregationRequest::Avg { .. } => AggregationResult::Value { value: avg },
+        AggregationRequest::Sum { .. } => AggregationResult::Value { value: Some(sum) },
+        _ => AggregationResult::Stats(StatsResult {
+            count,
+            min,
+            max,
+            avg,
+            sum,
+        }),
+    }
+}
+
+/// The key of a range without an explicit key, as in Elasticsearch: `*-100`, `100-200`, `200-*`.
+fn range_key(range: &RangeRequest) -> String {
+    if let Some(key) = &range.key {
+        return key.clone();
+    }
+    let bound =
+        |bound: Option<f64>| bound.map_or_else(|| "*".to_string(), |bound| bound.to_string());
+    format!("{}-{}", bound(range.from), bound(range.to))
+}
+
+impl Collector for AggregationCollector {
+    type Fruit = AggregationFruit;
+    type Child = AggregationSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let mut collectors = vec![];
+        for aggregation in &self.aggregations {
+            let values = match &aggregation.kind {
+                AggregationKind::Tantivy(collector) => SegmentValues::Tantivy(Box::new(
+                    collector.for_segment(segment_local_id, segment)?,
+                )),
+                _ => SegmentValues::Counts(
+                    segment.fast_fields().u64_lenient(aggregation.field)?,
+                    HashMap::new(),
+                ),
+            };
+            collectors.push((aggregation.exists.scorer(segment, 1.0)?, values));
+        }
+        Ok(AggregationSegmentCollector { collectors })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Vec<SegmentFruit>>) -> tantivy::Result<Self::Fruit> {
+        let mut fruits: Vec<Vec<SegmentFruit>> = self.aggregations.iter().map(|_| vec![]).collect();
+        for segment_fruit in segment_fruits {
+            for (fruits, fruit) in fruits.iter_mut().zip(segment_fruit) {
+                fruits.push(fruit);
+            }
+        }
+        let mut results = BTreeMap::new();
+        for (aggregation, fruits) in self.aggregations.iter().zip(fruits) {
+            let result = match &aggregation.kind {
+                AggregationKind::Tantivy(collector) => {
+                    let fruits = fruits
+                        .into_iter()
+                        .filter_map(|fruit| match fruit {
+                            SegmentFruit::Tantivy(fruit) => Some(fruit),
+                            SegmentFruit::Counts(_) => None,
+                        })
+                        .collect();
+                    let mut result = collector.merge_fruits(fruits)?;
+                    match result.0.remove(&aggregation.name) {
+                        Some(result) => tantivy_result(aggregation, result),
+                        None => AggregationResult::Value { value: None },
+                    }
+                }
+                _ => {
+                    let mut counts = HashMap::new();
+                    for fruit in fruits {
+                        if let SegmentFruit::Counts(segment_counts) = fruit {
+                            for (value, count) in segment_counts {
+                                *counts.entry(value).or_insert(0) += count;
+                            }
+                        }
+                    }
+                    counted_result(aggregation, counts)
+                }
+            };
+            results.insert(aggregation.name.clone(), result);
+        }
+        Ok(AggregationFruit(results))
+    }
+}
+
+enum SegmentValues {
+    Tantivy(Box<TantivySegmentCollector>),
+    /// The reader of the fast field and the number of hits for each value.
+    Counts(DynamicFastFieldReader<u64>, HashMap<u64, u64>),
+}
+
+pub enum SegmentFruit {
+    Tantivy(tantivy::Result<IntermediateAggregationResults>),
+    Counts(HashMap<u64, u64>),
+}
+
+pub struct AggregationSegmentCollector {
+    collectors: Vec<(Box<dyn Scorer>, SegmentValues)>,
+}
+
+impl SegmentCollector for AggregationSegmentCollector {
+    type Fruit = Vec<SegmentFruit>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        for (exists, values) in &mut self.collectors {
+            // Hits are collected in the order of their ids.
+            if exists.doc() < doc {
+                exists.seek(doc);
+            }
+            if exists.doc() != doc {
+                continue;
+            }
+            match values {
+                SegmentValues::Tantivy(collector) => collector.collect(doc, score),
+                SegmentValues::Counts(reader, counts) => {
+                    *counts.entry(reader.get(doc)).or_insert(0) += 1
+                }
+            }
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.collectors
+            .into_iter()
+            .map(|(_exists, values)| match values {
+                SegmentValues::Tantivy(collector) => SegmentFruit::Tantivy(collector.harvest()),
+                SegmentValues::Counts(_reader, counts) => SegmentFruit::Counts(counts),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture::Fixture;
+    use crate::search::ResultOptions;
+    use tantivy::schema::{Schema, FAST, INDEXED, STORED, STRING, TEXT};
+
+    #[test]
+    fn search_aggregations() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("body", TEXT);
+        schema_builder.add_text_field("tag", STRING | FAST);
+        schema_builder.add_u64_field("size", INDEXED | FAST);
+        schema_builder.add_i64_field("delta", INDEXED | FAST);
+        schema_builder.add_date_field("published", INDEXED | FAST);
+        schema_builder.add_u64_field("stored", STORED | FAST);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([
+            { "body": "x", "tag": "a", "size": 100, "delta": -3, "published": "2020-01-01T10:00:00Z" },
+            { "body": "x", "tag": "b", "size": 1500, "delta": 4, "published": "2020-01-02T10:00:00Z" },
+            { "body": "x", "tag": "b", "size": 2500, "delta": 4, "published": "2020-01-02T20:00:00Z" },
+            { "body": "y", "tag": "b", "size": 9000, "delta": 0 },
+            // Without values, not aggregated.
+            { "body": "x", "stored": 1 },
+        ]));
+
+        let search = |aggregations: serde_json::Value| {
+            let result_options = ResultOptions {
+                aggregations: serde_json::from_value(aggregations).unwrap(),
+                ..Default::default()
+            };
+            let search = serde_json::json!({ "query": { "raw": "x" }, "limit": 10 });
+            fixture
+                .search(search, &result_options)
+                .map(|results| serde_json::to_value(results.aggregations).unwrap())
+        };
+
+        let aggregations = search(serde_json::json!({
+            "sizes": { "range": { "field": "size", "ranges": [{ "to": 1000 }, { "from": 2000 }] } },
+            "histogram": { "histogram": { "field": "size", "interval": 1000 } },
+            "deltas": { "terms": { "field": "delta", "size": 1 } },
+            "tags": { "terms": { "field": "tag" } },
+            "days": { "date_histogram": { "field": "published", "interval": "1d" } },
+            "min_delta": { "min": { "field": "delta" } },
+            "stats": { "stats": { "field": "size" } },
+            "first": { "min": { "field": "published" } },
+            "published": { "stats": { "field": "published" } },
+            "published_days": { "range": { "field": "published", "ranges": [
+                { "to": 1577923200000i64 },
+                { "from": 1577923200000i64 },
+            ] } },
+        }))
+        .unwrap();
+        assert_eq!(
+            aggregations,
+            serde_json::json!({
+                "days": { "buckets": [
+                    { "key": 1577836800000i64, "doc_count": 1 },
+                    { "key": 1577923200000i64, "doc_count": 2 },
+                ] },
+                "deltas": { "buckets": [{ "key": 4, "doc_count": 2 }] },
+                "first": { "value": 1577872800000.0 },
+                "histogram": { "buckets": [
+                    { "key": 0, "doc_count": 1 },
+                    { "key": 1000, "doc_count": 1 },
+                    { "key": 2000, "doc_count": 1 },
+                ] },
+                "min_delta": { "value": -3.0 },
+                "published": {
+                    "count": 3,
+                    "min": 1577872800000.0,
+                    "max": 1577995200000.0,
+                    "avg": 1577942400000.0,
+                    "sum": 4733827200000.0,
+                },
+                "published_days": { "buckets": [
+                    { "key": "*-1577923200000", "to": 1577923200000.0, "doc_count": 1 },
+                    { "key": "1577923200000-*", "from": 1577923200000.0, "doc_count": 2 },
+                ] },
+                "sizes": { "buckets": [
+                    { "key": "*-1000", "to": 1000.0, "doc_count": 1 },
+                    { "key": "2000-*", "from": 2000.0, "doc_count": 1 },
+                ] },
+                "stats": { "count": 3, "min": 100.0, "max": 2500.0, "avg": 1366.6666666666667, "sum": 4100.0 },
+                "tags": { "buckets": [
+                    { "key": "b", "doc_count": 2 },
+                    { "key": "a", "doc_count": 1 },
+                ] },
+            })
+        );
+
+        let invalid = [
+            // Not indexed
+            serde_json::json!({ "a": { "avg": { "field": "stored" } } }),
+            // Not a fast field
+            serde_json::json!({ "a": { "avg": { "field": "body" } } }),
+            serde_json::json!({ "a": { "avg": { "field": "tag" } } }),
+            serde_json::json!({ "a": { "histogram": { "field": "published", "interval": 1000 } } }),
+            serde_json::json!({ "a": { "date_histogram": { "field": "size", "interval": "1d" } } }),
+            serde_json::json!({ "a": { "date_histogram": { "field": "published", "interval": "1y" } } }),
+            serde_json::json!({ "a": { "range": { "field": "size", "ranges": [] } } }),
+            serde_json::json!({ "a": { "histogram": { "field": "size", "interval": 0 } } }),
+        ];
+        for aggregations in invalid {
+            assert!(search(aggregations.clone()).is_err(), "{}", aggregations);
+        }
+    }
+}
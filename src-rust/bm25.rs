@@ -0,0 +1,542 @@
+use std::collections::{BTreeMap, HashMap};
+use tantivy::fieldnorm::FieldNormReader;
+use tantivy::postings::{Postings, SegmentPostings};
+use tantivy::query::{
+    BooleanQuery, BoostQuery, EmptyScorer, Explanation, Occur, PhraseQuery, Query, RangeQuery,
+    Scorer, TermQuery, Weight,
+};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Term};
+use tantivy::{DocId, DocSet, Result, Score, Searcher, SegmentReader, TantivyError};
+
+/// The parameters of BM25 in tantivy.
+const K1: Score = 1.2;
+const B: Score = 0.75;
+
+/// Document and token counts of the terms of a query in one or more indexes.
+///
+/// Terms are identified by field name and value, so that statistics of indexes
+/// with different schemas can be added up.
+#[derive(Debug, Default)]
+pub struct TermStatistics {
+    /// The number of documents. As in tantivy, deleted documents are counted until
+    /// their segment is merged.
+    pub num_docs: u64,
+    /// The number of tokens in each field of the terms.
+    pub num_tokens: HashMap<String, u64>,
+    pub doc_freqs: HashMap<(String, Vec<u8>), u64>,
+}
+
+impl TermStatistics {
+    /// Count the terms of a query in the index of a searcher.
+    pub fn for_query(searcher: &Searcher, query: &dyn Query) -> Result<TermStatistics> {
+        let schema = searcher.schema();
+        let mut terms = BTreeMap::new();
+        query.query_terms(&mut terms);
+        let mut statistics = TermStatistics::default();
+        for segment in searcher.segment_readers() {
+            statistics.num_docs += u64::from(segment.max_doc());
+        }
+        for term in terms.keys() {
+            let field_name = schema.get_field_name(term.field()).to_string();
+            if !statistics.num_tokens.contains_key(&field_name) {
+                let mut num_tokens = 0;
+                for segment in searcher.segment_readers() {
+                    num_tokens += segment.inverted_index(term.field())?.total_num_tokens();
+                }
+                statistics.num_tokens.insert(field_name.clone(), num_tokens);
+            }
+            let key = (field_name, term.value_bytes().to_vec());
+            statistics.doc_freqs.insert(key, searcher.doc_freq(term)?);
+        }
+        Ok(statistics)
+    }
+
+    pub fn sum(statistics: &[TermStatistics]) -> TermStatistics {
+        let mut sum = TermStatistics::default();
+        for statistics in statistics {
+            sum.num_docs += statistics.num_docs;
+            for (field_name, num_tokens) in &statistics.num_tokens {
+                *sum.num_tokens.entry(field_name.clone()).or_insert(0) += num_tokens;
+            }
+            for (term, doc_freq) in &statistics.doc_freqs {
+                *sum.doc_freqs.entry(term.clone()).or_insert(0) += doc_freq;
+            }
+        }
+        sum
+    }
+}
+
+/// BM25 with the statistics of several indexes, for the terms of a field.
+#[derive(Debug, Clone)]
+struct Bm25 {
+    /// The IDF times `K1 + 1` and the boost of the field.
+    weight: Score,
+    /// The length normalization for each fieldnorm id.
+    cache: Vec<Score>,
+}
+
+impl Bm25 {
+    /// The BM25 weight of terms of a field. As in tantivy, the IDF of a phrase is the sum of the
+    /// IDFs of its terms.
+    fn new(schema: &Schema, terms: &[Term], statistics: &TermStatistics, boost: Score) -> Bm25 {
+        let num_docs = statistics.num_docs;
+        let mut idf_sum = 0.0;
+        let mut average_fieldnorm = 1.0;
+        if num_docs > 0 {
+            for term in terms {
+                let field_name = schema.get_field_name(term.field());
+                let key = (field_name.to_string(), term.value_bytes().to_vec());
+                let doc_freq = statistics.doc_freqs.get(&key).copied().unwrap_or(0);
+                idf_sum += idf(doc_freq.min(num_docs), num_docs);
+                let num_tokens = statistics.num_tokens.get(field_name).copied().unwrap_or(0);
+                average_fieldnorm = num_tokens as Score / num_docs as Score;
+            }
+        }
+        let cache = (0..=255u8)
+            .map(|fieldnorm_id| {
+                let fieldnorm = FieldNormReader::id_to_fieldnorm(fieldnorm_id) as Score;
+                K1 * (1.0 - B + B * fieldnorm / average_fieldnorm)
+            })
+            .collect();
+        Bm25 {
+            weight: boost * idf_sum * (1.0 + K1),
+            cache,
+        }
+    }
+
+    fn score(&self, fieldnorm_id: u8, term_freq: u32) -> Score {
+        let term_freq = term_freq as Score;
+        self.weight * term_freq / (term_freq + self.cache[fieldnorm_id as usize])
+    }
+}
+
+fn idf(doc_freq: u64, num_docs: u64) -> Score {
+    let x = ((num_docs - doc_freq) as Score + 0.5) / (doc_freq as Score + 0.5);
+    (1.0 + x).ln()
+}
+
+fn fieldnorms(reader: &SegmentReader, field: Field) -> FieldNormReader {
+    reader
+        .get_fieldnorms_reader(field)
+        .unwrap_or_else(|_| FieldNormReader::constant(reader.max_doc(), 1))
+}
+
+/// Score the documents that match a query with BM25, using the statistics of several indexes.
+///
+/// Term and phrase queries are scored as tantivy scores them, with the given statistics and the
+/// boosts of their fields. Boolean queries keep their clauses, so they combine these scores as
+/// tantivy does. Queries without terms, such as ranges and the queries of the `prefix` and
+/// `fuzzy` modes, have scores that don't depend on the index and are kept as they are.
+///
+/// Field boosts must not be in the query already. Other boosts of terms can't be read from a
+/// query, so queries with them are rejected.
+pub fn with_statistics(
+    schema: &Schema,
+    query: &dyn Query,
+    statistics: &TermStatistics,
+    boosts: &HashMap<String, Score>,
+) -> Result<Box<dyn Query>> {
+    let boost = |field: Field| {
+        let boost = boosts.get(schema.get_field_name(field));
+        boost.copied().unwrap_or(1.0)
+    };
+    if let Some(boolean_query) = query.downcast_ref::<BooleanQuery>() {
+        let mut clauses = vec![];
+        for (occur, clause) in boolean_query.clauses() {
+            // Excluded documents are not scored.
+            let clause = match occur {
+                Occur::MustNot => clause.box_clone(),
+                _ => with_statistics(schema, &**clause, statistics, boosts)?,
+            };
+            clauses.push((*occur, clause));
+        }
+        return Ok(Box::new(BooleanQuery::new(clauses)));
+    }
+    if let Some(term_query) = query.downcast_ref::<TermQuery>() {
+        let term = term_query.term().clone();
+        let bm25 = Bm25::new(
+            schema,
+            std::slice::from_ref(&term),
+            statistics,
+            boost(term.field()),
+        );
+        return Ok(Box::new(GlobalTermQuery { term, bm25 }));
+    }
+    if let Some(phrase_query) = query.downcast_ref::<PhraseQuery>() {
+        let terms = phrase_query.phrase_terms();
+        let bm25 = Bm25::new(schema, &terms, statistics, boost(phrase_query.field()));
+        return Ok(Box::new(GlobalPhraseQuery {
+            query: phrase_query.clone(),
+            terms,
+            bm25,
+        }));
+    }
+    if let Some(range_query) = query.downcast_ref::<RangeQuery>() {
+        let boost = boost(range_query.field());
+        if (boost - 1.0).abs() > Score::EPSILON {
+            return Ok(Box::new(BoostQuery::new(query.box_clone(), boost)));
+        }
+        return Ok(query.box_clone());
+    }
+    let mut terms = BTreeMap::new();
+    query.query_terms(&mut terms);
+    if terms.is_empty() {
+        return Ok(query.box_clone());
+    }
+    if query.downcast_ref::<BoostQuery>().is_some() {
+        return Err(TantivyError::InvalidArgument(
+            "Boosts in the query syntax are not supported when merging indexes, use field boosts"
+                .to_string(),
+        ));
+    }
+    Err(TantivyError::InvalidArgument(format!(
+        "Query not supported when merging indexes: {:?}",
+        query
+    )))
+}
+
+fn does_not_match(doc: DocId) -> TantivyError {
+    TantivyError::InvalidArgument(format!("Document #({}) does not match", doc))
+}
+
+/// A term query scored with the statistics of several indexes.
+#[derive(Debug, Clone)]
+struct GlobalTermQuery {
+    term: Term,
+    bm25: Bm25,
+}
+
+impl Query for GlobalTermQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> Result<Box<dyn Weight>> {
+        let query = TermQuery::new(self.term.clone(), IndexRecordOption::WithFreqs);
+        if !scoring_enabled {
+            return query.weight(searcher, false);
+        }
+        Ok(Box::new(self.clone()))
+    }
+
+    fn query_terms(&self, terms: &mut BTreeMap<Term, bool>) {
+        terms.insert(self.term.clone(), false);
+    }
+}
+
+impl Weight for GlobalTermQuery {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> Result<Box<dyn Scorer>> {
+        let field = self.term.field();
+        let postings = reader
+            .inverted_index(field)?
+            .read_postings(&self.term, IndexRecordOption::WithFreqs)?;
+        Ok(match postings {
+            Some(postings) => Box::new(GlobalTermScorer {
+                postings,
+                fieldnorms: fieldnorms(reader, field),
+                bm25: self.bm25.clone(),
+                boost,
+            }),
+            None => Box::new(EmptyScorer),
+        })
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(does_not_match(doc));
+        }
+        Ok(Explanation::new(
+            "BM25 with the statistics of several indexes",
+            scorer.score(),
+        ))
+    }
+}
+
+struct GlobalTermScorer {
+    postings: SegmentPostings,
+    fieldnorms: FieldNormReader,
+    bm25: Bm25,
+    boost: Score,
+}
+
+impl DocSet for GlobalTermScorer {
+    fn advance(&mut self) -> DocId {
+        self.postings.advance()
+    }
+
+    fn seek(&mut self, target: DocId) -> DocId {
+        self.postings.seek(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.postings.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.postings.size_hint()
+    }
+}
+
+impl Scorer for GlobalTermScorer {
+    fn score(&mut self) -> Score {
+        let doc = self.postings.doc();
+        let fieldnorm_id = self.fieldnorms.fieldnorm_id(doc);
+        self.boost * self.bm25.score(fieldnorm_id, self.postings.term_freq())
+    }
+}
+
+/// A phrase query scored with the statistics of several indexes.
+#[derive(Debug, Clone)]
+struct GlobalPhraseQuery {
+    query: PhraseQuery,
+    terms: Vec<Term>,
+    bm25: Bm25,
+}
+
+impl Query for GlobalPhraseQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> Result<Box<dyn Weight>> {
+        let weight = self.query.weight(searcher, false)?;
+        if !scoring_enabled {
+            return Ok(weight);
+        }
+        Ok(Box::new(GlobalPhraseWeight {
+            weight,
+            terms: self.terms.clone(),
+            bm25: self.bm25.clone(),
+        }))
+    }
+
+    fn query_terms(&self, terms: &mut BTreeMap<Term, bool>) {
+        self.query.query_terms(terms);
+    }
+}
+
+struct GlobalPhraseWeight {
+    /// The weight of the phrase query, without scores.
+    weight: Box<dyn Weight>,
+    terms: Vec<Term>,
+    bm25: Bm25,
+}
+
+impl Weight for GlobalPhraseWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> Result<Box<dyn Scorer>> {
+        let docs = self.weight.scorer(reader, boost)?;
+        let mut postings = vec![];
+        for term in &self.terms {
+            match reader
+                .inverted_index(term.field())?
+                .read_postings(term, IndexRecordOption::WithFreqsAndPositions)?
+            {
+                Some(term_postings) => postings.push(term_postings),
+                // Documents of this segment don't have the phrase.
+                None => return Ok(docs),
+            }
+        }
+        let field = self.terms[0].field();
+        Ok(Box::new(GlobalPhraseScorer {
+            docs,
+            postings,
+            fieldnorms: fieldnorms(reader, field),
+            bm25: self.bm25.clone(),
+            boost,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(does_not_match(doc));
+        }
+        Ok(Explanation::new(
+            "BM25 with the statistics of several indexes",
+            scorer.score(),
+        ))
+    }
+}
+
+struct GlobalPhraseScorer {
+    docs: Box<dyn Scorer>,
+    /// The postings of the terms of the phrase, in the order of the phrase.
+    postings: Vec<SegmentPostings>,
+    fieldnorms: FieldNormReader,
+    bm25: Bm25,
+    boost: Score,
+}
+
+impl GlobalPhraseScorer {
+    /// The number of times the phrase occurs in the current document.
+    ///
+    /// The terms of the phrase are expected at consecutive positions, as the tokenizers of
+    /// tantivy don't skip positions.
+    fn phrase_count(&mut self) -> u32 {
+        let doc = self.docs.doc();
+        let mut positions = vec![];
+        for (offset, postings) in self.postings.iter_mut().enumerate() {
+            if postings.doc() < doc {
+                postings.seek(doc);
+            }
+            let mut term_positions = vec![];
+            postings.positions(&mut term_positions);
+            positions.push((offset as u32, term_positions));
+        }
+        let (first, rest) = positions.split_first().expect("a phrase has terms");
+        first
+            .1
+            .iter()
+            .filter(|&&start| {
+                rest.iter().all(|(offset, term_positions)| {
+                    term_positions.binary_search(&(start + offset)).is_ok()
+                })
+            })
+            .count() as u32
+    }
+}
+
+impl DocSet for GlobalPhraseScorer {
+    fn advance(&mut self) -> DocId {
+        self.docs.advance()
+    }
+
+    fn seek(&mut self, target: DocId) -> DocId {
+        self.docs.seek(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.docs.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.docs.size_hint()
+    }
+}
+
+impl Scorer for GlobalPhraseScorer {
+    fn score(&mut self) -> Score {
+        let fieldnorm_id = self.fieldnorms.fieldnorm_id(self.docs.doc());
+        let phrase_count = self.phrase_count();
+        self.boost * self.bm25.score(fieldnorm_id, phrase_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collector::Interrupt;
+    use crate::document::{DocumentInput, OnError};
+    use crate::fixture::Fixture;
+    use crate::index::IndexCatalog;
+    use crate::options::QueryOptions;
+    use std::collections::{BTreeSet, HashMap};
+    use tantivy::schema::{Schema, STORED, TEXT};
+
+    #[test]
+    fn scores_with_own_statistics() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("title", TEXT | STORED);
+        schema_builder.add_text_field("body", TEXT | STORED);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([
+            { "title": "hello world", "body": "a b c" },
+            { "title": "hello", "body": "world world hello" },
+            { "title": "world of worlds", "body": "hello there" },
+            { "title": "nothing", "body": "to see here at all" },
+            { "title": "hello world hello world", "body": "world hello" },
+        ]));
+
+        // With the statistics of the index itself, the scores are those of tantivy.
+        let boosts: HashMap<String, f32> = vec![("title".to_string(), 2.0)].into_iter().collect();
+        let boosted = QueryOptions {
+            boosts: Some(boosts),
+            ..Default::default()
+        };
+        let queries = [
+            "hello",
+            "hello world",
+            "+title:hello body:world",
+            "hello -body:there",
+            "\"hello world\"",
+            "\"world hello\" there",
+            "+hello +(world \"a b\")",
+        ];
+        for options in &[QueryOptions::default(), boosted] {
+            for query in &queries {
+                let statistics = fixture.handle.term_statistics(query, options).unwrap();
+                let expected = fixture
+                    .handle
+                    .query(query, options, &Interrupt::none())
+                    .unwrap();
+                let results = fixture
+                    .handle
+                    .query_with_statistics(query, options, Some(&statistics), &Interrupt::none())
+                    .unwrap();
+                assert_eq!(results.total_hits, expected.total_hits, "{}", query);
+                for (hit, expected) in results.hits.iter().zip(&expected.hits) {
+                    assert_eq!(hit.address.doc_id, expected.address.doc_id, "{}", query);
+                    assert!((hit.score - expected.score).abs() < 1e-5, "{}", query);
+                }
+            }
+        }
+
+        let statistics = fixture
+            .handle
+            .term_statistics("hello^2", &Default::default());
+        let result = fixture.handle.query_with_statistics(
+            "hello^2",
+            &Default::default(),
+            Some(&statistics.unwrap()),
+            &Interrupt::none(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn merged_hits() {
+        let tmp_dir = tempdir::TempDir::new("test").unwrap();
+        let catalog = IndexCatalog::new(tmp_dir.path().to_path_buf()).unwrap();
+        let titles = [
+            ("a", vec!["hello world", "world hello", "hello there"]),
+            ("b", vec!["hello world again", "hello", "world"]),
+        ];
+        for (name, titles) in titles.iter() {
+            let mut schema_builder = Schema::builder();
+            schema_builder.add_text_field("title", TEXT | STORED);
+            catalog
+                .create_ram_index(name.to_string(), schema_builder.build(), Default::default())
+                .unwrap();
+            let docs: Vec<DocumentInput> = titles
+                .iter()
+                .map(|title| serde_json::from_value(serde_json::json!({ "title": title })).unwrap())
+                .collect();
+            let handle = catalog.get_index(name).unwrap();
+            handle
+                .add_documents(&docs, Some(true), OnError::Reject)
+                .unwrap();
+        }
+
+        let indexes = vec!["a".to_string(), "b".to_string()];
+        for query in &["\"hello world\"", "hello -world", "world -\"hello world\""] {
+            let merged: BTreeSet<(String, u32)> = catalog
+                .query_merged(query, &indexes, &Default::default(), &Interrupt::none())
+                .unwrap()
+                .hits
+                .into_iter()
+                .map(|(name, hit)| (name, hit.address.doc_id))
+                .collect();
+            let unmerged: BTreeSet<(String, u32)> = catalog
+                .query_multi(
+                    query,
+                    &indexes,
+                    &Default::default(),
+                    &Default::default(),
+                    &Interrupt::none(),
+                )
+                .unwrap()
+                .into_iter()
+                .flat_map(|(name, results)| {
+                    results
+                        .hits
+                        .into_iter()
+                        .map(move |hit| (name.clone(), hit.address.doc_id))
+                })
+                .collect();
+            assert!(!merged.is_empty(), "{}", query);
+            assert_eq!(merged, unmerged, "{}", query);
+        }
+    }
+}
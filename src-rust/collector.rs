@@ -0,0 +1,420 @@
+use crate::error::{ErrorCode, RpcError};
+use crate::rpc::CancelToken;
+use crate::sort::{Sort, SortKeys};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::{DocAddress, DocId, Score, SegmentOrdinal, SegmentReader, TantivyError};
+
+/// Number of documents collected between two checks of the interrupt.
+const CHECK_INTERVAL: u32 = 1024;
+
+/// Interrupt
+///
+/// Tells a running search to stop, either because the request was cancelled
+/// or because its timeout has passed.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    cancel_token: CancelToken,
+    timeout_ms: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+impl Interrupt {
+    /// Create an interrupt for a request. The timeout starts now.
+    pub fn new(cancel_token: &CancelToken, timeout_ms: Option<u64>) -> Self {
+        Interrupt {
+            cancel_token: cancel_token.clone(),
+            timeout_ms,
+            deadline: timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms)),
+        }
+    }
+
+    /// An interrupt that never fires.
+    #[allow(dead_code)]
+    pub fn none() -> Self {
+        Interrupt::default()
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.cancel_token.is_cancelled() || self.is_timed_out()
+    }
+
+    fn is_timed_out(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Returns the error to send to the client if the request was interrupted.
+    pub fn check(&self) -> Result<(), RpcError> {
+        if self.cancel_token.is_cancelled() {
+            Err(RpcError::cancelled())
+        } else if self.is_timed_out() {
+            Err(RpcError::timeout(self.timeout_ms.unwrap_or_default()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wrap a collector so that it stops collecting once the request is interrupted.
+    pub fn wrap<C: Collector>(&self, collector: C) -> Interruptible<C> {
+        Interruptible {
+            collector,
+            interrupt: self.clone(),
+        }
+    }
+}
+
+/// A collector that stops collecting once its interrupt fires.
+///
+/// The search itself runs on until the end of the current segment, but all
+/// further documents are ignored and no further segments are collected.
+/// Searches with an interrupted collector fail, use `Interrupt::check` to get
+/// the error for the client.
+pub struct Interruptible<C> {
+    collector: C,
+    interrupt: Interrupt,
+}
+
+impl<C: Collector> Collector for Interruptible<C> {
+    type Fruit = C::Fruit;
+    type Child = InterruptibleSegment<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        if self.interrupt.is_interrupted() {
+            return Err(interrupted());
+        }
+        Ok(InterruptibleSegment {
+            collector: self.collector.for_segment(segment_local_id, segment)?,
+            interrupt: self.interrupt.clone(),
+            until_check: CHECK_INTERVAL,
+            stopped: false,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.collector.requires_scoring()
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<Self::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        if self.interrupt.is_interrupted() {
+            return Err(interrupted());
+        }
+        self.collector.merge_fruits(segment_fruits)
+    }
+}
+
+pub struct InterruptibleSegment<S> {
+    collector: S,
+    interrupt: Interrupt,
+    /// Hits to collect until the interrupt is checked again.
+    until_check: u32,
+    stopped: bool,
+}
+
+impl<S: SegmentCollector> SegmentCollector for InterruptibleSegment<S> {
+    type Fruit = S::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if self.stopped {
+            return;
+        }
+        self.until_check -= 1;
+        if self.until_check == 0 {
+            self.until_check = CHECK_INTERVAL;
+            if self.interrupt.is_interrupted() {
+                self.stopped = true;
+                return;
+            }
+        }
+        self.collector.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.collector.harvest()
+    }
+}
+
+fn interrupted() -> TantivyError {
+    TantivyError::SystemError("Search was interrupted.".to_string())
+}
+
+/// The address of a hit, as sent to clients.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct HitAddress {
+    pub segment_ord: SegmentOrdinal,
+    pub doc_id: DocId,
+}
+
+impl From<DocAddress> for HitAddress {
+    fn from(address: DocAddress) -> Self {
+        HitAddress {
+            segment_ord: address.segment_ord,
+            doc_id: address.doc_id,
+        }
+    }
+}
+
+/// Cursor
+///
+/// The position of a hit in the results of a search, to continue the search after it.
+/// `values` is the sort key of the hit: The bits of the score, or the key of the sort fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub values: Vec<u64>,
+    pub address: DocAddress,
+}
+
+impl Cursor {
+    pub fn from_score(score: Score, address: DocAddress) -> Self {
+        Cursor {
+            values: vec![score.to_bits() as u64],
+            address,
+        }
+    }
+
+    fn score(&self) -> Score {
+        Score::from_bits(self.values[0] as u32)
+    }
+
+    /// Encode the cursor as an opaque string for clients.
+    pub fn encode(&self) -> String {
+        let mut cursor = String::new();
+        for value in &self.values {
+            cursor.push_str(&format!("{:016x}", value));
+        }
+        cursor.push_str(&format!(
+            "{:08x}{:08x}",
+            self.address.segment_ord, self.address.doc_id
+        ));
+        cursor
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, RpcError> {
+        let invalid = || {
+            RpcError::new(ErrorCode::InvalidArgument, "Invalid cursor.")
+                .with_details(serde_json::json!({ "cursor": cursor }))
+        };
+        if cursor.len() < 32 || !cursor.is_ascii() {
+            return Err(invalid());
+        }
+        let (values, address) = cursor.split_at(cursor.len() - 16);
+        let chunks = values.as_bytes().chunks_exact(16);
+        if !chunks.remainder().is_empty() {
+            return Err(invalid());
+        }
+        let values = chunks
+            .map(|value| {
+                // The cursor is ASCII, so the chunks are valid UTF-8.
+                let value = std::str::from_utf8(value).map_err(|_| invalid())?;
+                u64::from_str_radix(value, 16).map_err(|_| invalid())
+            })
+            .collect::<Result<Vec<u64>, RpcError>>()?;
+        let segment_ord = u32::from_str_radix(&address[0..8], 16).map_err(|_| invalid())?;
+        let doc_id = u32::from_str_radix(&address[8..16], 16).map_err(|_| invalid())?;
+        Ok(Cursor {
+            values,
+            address: DocAddress::new(segment_ord, doc_id),
+        })
+    }
+}
+
+/// A collector that only collects the hits after a cursor.
+///
+/// Hits are ordered by score (or by the key of the sort fields if `sort` is set),
+/// descending, and then by doc address. This is the order of `TopDocs`,
+/// so wrapping `TopDocs` returns the next page of results.
+///
+/// Doc addresses change when segments are merged, so pages may overlap if the
+/// index is changed between two requests.
+pub struct SearchAfter<C> {
+    collector: C,
+    cursor: Option<Cursor>,
+    sort: Option<Sort>,
+}
+
+impl<C: Collector> SearchAfter<C> {
+    pub fn new(collector: C, cursor: Option<Cursor>, sort: Option<Sort>) -> Self {
+        SearchAfter {
+            collector,
+            cursor,
+            sort,
+        }
+    }
+}
+
+impl<C: Collector> Collector for SearchAfter<C> {
+    type Fruit = C::Fruit;
+    type Child = SearchAfterSegment<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let sort_keys = match (&self.cursor, &self.sort) {
+            (Some(_), Some(sort)) => Some(sort.segment_keys(segment)?),
+            _ => None,
+        };
+        Ok(SearchAfterSegment {
+            collector: self.collector.for_segment(segment_local_id, segment)?,
+            cursor: self.cursor.clone(),
+            segment_ord: segment_local_id,
+            sort_keys,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.collector.requires_scoring() || (self.cursor.is_some() && self.sort.is_none())
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<Self::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        self.collector.merge_fruits(segment_fruits)
+    }
+}
+
+pub struct SearchAfterSegment<S> {
+    collector: S,
+    cursor: Option<Cursor>,
+    segment_ord: SegmentOrdinal,
+    sort_keys: Option<SortKeys>,
+}
+
+impl<S> SearchAfterSegment<S> {
+    fn is_after_cursor(&self, doc: DocId, score: Score) -> bool {
+        let cursor = match &self.cursor {
+            Some(cursor) => cursor,
+            None => return true,
+        };
+        let ordering = match &self.sort_keys {
+            Some(sort_keys) => cursor.values.cmp(&sort_keys.key(doc)),
+            None => cursor
+                .score()
+                .partial_cmp(&score)
+                .unwrap_or(std::cmp::Ordering::Equal),
+        };
+        match ordering {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Less => false,
+            std::cmp::Ordering::Equal => {
+                (self.segment_ord, doc) > (cursor.address.segment_ord, cursor.address.doc_id)
+            }
+        }
+    }
+}
+
+impl<S: SegmentCollector> SegmentCollector for SearchAfterSegment<S> {
+    type Fruit = S::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if self.is_after_cursor(doc, score) {
+            self.collector.collect(doc, score);
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.collector.harvest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::fixture::Fixture;
+    use crate::index::QueryHit;
+    use crate::options::QueryOptions;
+    use tantivy::schema::{Schema, STORED, STRING, TEXT};
+
+    #[test]
+    fn interrupt_query() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("title", TEXT | STORED);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([{ "title": "hello world" }]));
+        let handle = &fixture.handle;
+        let options = QueryOptions::default();
+
+        let cancel_token = CancelToken::default();
+        let interrupt = Interrupt::new(&cancel_token, Some(60_000));
+        assert_eq!(
+            handle
+                .query("hello", &options, &interrupt)
+                .unwrap()
+                .hits
+                .len(),
+            1
+        );
+
+        cancel_token.cancel();
+        let err = handle.query("hello", &options, &interrupt).unwrap_err();
+        let err = RpcError::from(err);
+        assert_eq!(err.code, ErrorCode::Cancelled);
+
+        let interrupt = Interrupt::new(&Default::default(), Some(0));
+        let err = handle.query("hello", &options, &interrupt).unwrap_err();
+        assert_eq!(RpcError::from(err).code, ErrorCode::Timeout);
+    }
+
+    #[test]
+    fn paginate_query() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        schema_builder.add_text_field("title", TEXT | STORED);
+        let fixture = Fixture::new(schema_builder.build());
+        let docs: Vec<_> = (0..5)
+            .map(|i| serde_json::json!({ "id": i.to_string(), "title": "hello" }))
+            .collect();
+        fixture.add(serde_json::Value::Array(docs));
+        let handle = &fixture.handle;
+
+        let ids = |hits: &[QueryHit]| -> Vec<String> {
+            hits.iter()
+                .map(|hit| format!("{:?}", hit.doc.0["id"]))
+                .collect()
+        };
+        let options = QueryOptions {
+            limit: Some(5),
+            ..Default::default()
+        };
+        let all = handle.query("hello", &options, &Interrupt::none()).unwrap();
+        assert_eq!(all.hits.len(), 5);
+
+        let options = QueryOptions {
+            limit: Some(2),
+            offset: Some(2),
+            ..Default::default()
+        };
+        let page = handle.query("hello", &options, &Interrupt::none()).unwrap();
+        assert_eq!(ids(&page.hits), ids(&all.hits[2..4]));
+
+        let options = QueryOptions {
+            limit: Some(2),
+            cursor: Some(all.hits[2].cursor.clone()),
+            ..Default::default()
+        };
+        let page = handle.query("hello", &options, &Interrupt::none()).unwrap();
+        assert_eq!(ids(&page.hits), ids(&all.hits[3..5]));
+
+        let options = QueryOptions {
+            cursor: Some("invalid".to_string()),
+            ..Default::default()
+        };
+        let err = handle
+            .query("hello", &options, &Interrupt::none())
+            .unwrap_err();
+        assert_eq!(RpcError::from(err).code, ErrorCode::InvalidArgument);
+    }
+}
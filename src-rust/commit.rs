@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tantivy::{IndexWriter, Result};
+
+/// Commit policy
+///
+/// Decides when changes that were not committed explicitly are committed.
+/// If no limit is set, every change is committed right away.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CommitPolicy {
+    /// Commit once this many documents were added or deleted since the last commit.
+    pub max_docs: Option<u64>,
+    /// Commit pending changes at this interval.
+    pub interval_ms: Option<u64>,
+    /// Commit pending changes once there were no changes for this long.
+    pub idle_ms: Option<u64>,
+}
+
+impl CommitPolicy {
+    pub fn commits_every_change(&self) -> bool {
+        self.max_docs.is_none() && !self.is_timed()
+    }
+
+    pub fn is_timed(&self) -> bool {
+        self.interval_ms.is_some() || self.idle_ms.is_some()
+    }
+}
+
+/// How often the auto commit thread checks the timed commit policies.
+const AUTO_COMMIT_TICK: Duration = Duration::from_millis(100);
+
+pub struct CommitState {
+    pub policy: CommitPolicy,
+    pending: u64,
+    last_change: Instant,
+    last_commit: Instant,
+    /// Changes were added with `commit: false` or prepared for a commit. The commit policy
+    /// is suspended for all changes of the index until they are committed or rolled back.
+    transaction_open: bool,
+    auto_commit_running: bool,
+}
+
+impl CommitState {
+    pub fn new(policy: CommitPolicy) -> Self {
+        CommitState {
+            policy,
+            pending: 0,
+            last_change: Instant::now(),
+            last_commit: Instant::now(),
+            transaction_open: false,
+            auto_commit_running: false,
+        }
+    }
+
+    /// Record uncommitted changes and return true if the policy wants a commit now.
+    ///
+    /// Changes with `commit: false` open a transaction.
+    pub fn add_pending(&mut self, num_changes: u64, commit: Option<bool>) -> bool {
+        self.pending += num_changes;
+        self.last_change = Instant::now();
+        if commit == Some(false) {
+            self.transaction_open = true;
+        }
+        if self.transaction_open {
+            return false;
+        }
+        match self.policy.max_docs {
+            Some(max_docs) => self.pending >= max_docs,
+            None => self.policy.commits_every_change(),
+        }
+    }
+
+    /// Keep the commit policy from committing changes that were prepared for a commit.
+    pub fn open_transaction(&mut self) {
+        self.transaction_open = true;
+    }
+
+    pub fn reset(&mut self) {
+        self.pending = 0;
+        self.last_commit = Instant::now();
+        self.transaction_open = false;
+    }
+
+    fn timed_commit_due(&self) -> bool {
+        if self.pending == 0 || self.transaction_open {
+            return false;
+        }
+        let interval_due = self
+            .policy
+            .interval_ms
+            .map(|ms| self.last_commit.elapsed() >= Duration::from_millis(ms));
+        let idle_due = self
+            .policy
+            .idle_ms
+            .map(|ms| self.last_change.elapsed() >= Duration::from_millis(ms));
+        interval_due.unwrap_or(false) || idle_due.unwrap_or(false)
+    }
+}
+
+/// Commit all pending changes of a writer.
+pub fn commit_writer(
+    writer_lock: &RwLock<IndexWriter>,
+    state: &Mutex<CommitState>,
+    payload: Option<String>,
+) -> Result<()> {
+    let mut writer = writer_lock.write()?;
+    let mut prepared_commit = writer.prepare_commit()?;
+    if let Some(payload) = payload {
+        prepared_commit.set_payload(&payload);
+    }
+    let _opstamp = prepared_commit.commit()?;
+    // eprintln!("committed {:?}", _opstamp);
+    state.lock()?.reset();
+    Ok(())
+}
+
+/// Start a thread that commits pending changes according to the timed commit policies,
+/// if these are set and the thread is not running yet.
+///
+/// The thread stops once the policy has no timed limits anymore or the writer or the state
+/// was dropped. It holds neither, so that deleting an index releases the writer's lock.
+pub fn ensure_auto_commit(writer: &Arc<RwLock<IndexWriter>>, state: &Arc<Mutex<CommitState>>) {
+    {
+        let mut state = match state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if state.auto_commit_running || !state.policy.is_timed() {
+            return;
+        }
+        state.auto_commit_running = true;
+    }
+    let writer = Arc::downgrade(writer);
+    let state = Arc::downgrade(state);
+    thread::spawn(move || auto_commit_loop(writer, state));
+}
+
+fn auto_commit_loop(writer: Weak<RwLock<IndexWriter>>, state: Weak<Mutex<CommitState>>) {
+    loop {
+        thread::sleep(AUTO_COMMIT_TICK);
+        let (writer, state) = match (writer.upgrade(), state.upgrade()) {
+            (Some(writer), Some(state)) => (writer, state),
+            _ => return,
+        };
+        let commit_due = match state.lock() {
+            Ok(mut state) => {
+                if !state.policy.is_timed() {
+                    state.auto_commit_running = false;
+                    return;
+                }
+                state.timed_commit_due()
+            }
+            Err(_) => return,
+        };
+        if commit_due {
+            if let Err(err) = commit_writer(&writer, &state, None) {
+                eprintln!("Auto commit failed: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::Interrupt;
+    use crate::document::{DocumentInput, OnError};
+    use crate::fixture::Fixture;
+    use crate::index::IndexCatalog;
+    use crate::options::{IndexOptions, QueryOptions};
+    use tantivy::schema::{Schema, Value, STORED, STRING};
+
+    #[test]
+    fn rollback_with_timed_commit_policy() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        let options = IndexOptions {
+            commit_policy: CommitPolicy {
+                interval_ms: Some(50),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let fixture = Fixture::with_options(schema_builder.build(), options);
+        let handle = &fixture.handle;
+        let doc =
+            |id: &str| DocumentInput::Pairs(vec![("id".to_string(), Value::Str(id.to_string()))]);
+        let total_hits = || {
+            handle.get_reader().unwrap().reload().unwrap();
+            handle
+                .query("*", &QueryOptions::default(), &Interrupt::none())
+                .unwrap()
+                .total_hits
+        };
+
+        // The auto commit thread does not commit an open transaction.
+        handle
+            .add_documents(&[doc("1")], Some(false), OnError::Reject)
+            .unwrap();
+        handle.prepare_commit().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        handle.rollback().unwrap();
+        assert_eq!(total_hits(), 0);
+
+        // Changes without an explicit commit flag are committed by the policy again.
+        handle
+            .add_documents(&[doc("2")], None, OnError::Reject)
+            .unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(total_hits(), 1);
+    }
+
+    #[test]
+    fn recreate_index_with_timed_commit_policy() {
+        let tmp_dir = tempdir::TempDir::new("test").unwrap();
+        let catalog = IndexCatalog::new(tmp_dir.path().to_path_buf()).unwrap();
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        let schema = schema_builder.build();
+        let options = IndexOptions {
+            commit_policy: CommitPolicy {
+                interval_ms: Some(50),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let doc = || DocumentInput::Pairs(vec![("id".to_string(), Value::Str("1".to_string()))]);
+
+        // The auto commit thread must not keep the writer of a deleted index alive.
+        for _ in 0..2 {
+            catalog
+                .create_index("timed".to_string(), schema.clone(), options.clone())
+                .unwrap();
+            let handle = catalog.get_index("timed").unwrap();
+            handle
+                .add_documents(&[doc()], None, OnError::Reject)
+                .unwrap();
+            drop(handle);
+            catalog.delete_index("timed".to_string()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,604 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use tantivy::schema::{Document, Facet, Field, FieldType, NamedFieldDocument, Schema, Term, Value};
+use tantivy::{DateTime, Result, TantivyError};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// A document as sent by clients.
+///
+/// Either a JSON object with field names as keys (and arrays for multi-valued fields),
+/// or a list of field/value pairs.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum DocumentInput {
+    Object(Map<String, JsonValue>),
+    Pairs(Vec<(String, Value)>),
+}
+
+/// What to do with a batch of documents if some of them are invalid.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Add none of the documents.
+    #[default]
+    Reject,
+    /// Add the valid documents and skip the invalid ones.
+    Skip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentErrorKind {
+    UnknownField,
+    TypeMismatch,
+    MissingField,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentError {
+    /// Position of the document in the batch.
+    pub document: usize,
+    pub field: String,
+    pub kind: DocumentErrorKind,
+    pub message: String,
+}
+
+impl DocumentError {
+    fn new(document: usize, field: &str, kind: DocumentErrorKind, message: String) -> Self {
+        DocumentError {
+            document,
+            field: field.to_string(),
+            kind,
+            message,
+        }
+    }
+}
+
+/// Build tantivy documents from the documents sent by clients.
+///
+/// Returns the valid documents together with their position in the batch,
+/// and the errors of the invalid documents.
+pub fn build_documents(
+    schema: &Schema,
+    docs: &[DocumentInput],
+    required_fields: &[String],
+) -> (Vec<(usize, Document)>, Vec<DocumentError>) {
+    let mut documents = vec![];
+    let mut errors = vec![];
+    for (position, doc) in docs.iter().enumerate() {
+        let result = match doc {
+            DocumentInput::Pairs(pairs) => build_document(schema, pairs, required_fields, position),
+            DocumentInput::Object(object) => {
+                let (pairs, mut json_errors) = json_to_pairs(schema, object, position);
+                match build_document(schema, &pairs, required_fields, position) {
+                    Ok(_) if !json_errors.is_empty() => Err(json_errors),
+                    Ok(document) => Ok(document),
+                    Err(mut doc_errors) => {
+                        json_errors.append(&mut doc_errors);
+                        Err(json_errors)
+                    }
+                }
+            }
+        };
+        match result {
+            Ok(document) => documents.push((position, document)),
+            Err(mut doc_errors) => errors.append(&mut doc_errors),
+        }
+    }
+    (documents, errors)
+}
+
+/// Convert a JSON object into field/value pairs, parsing the values as the type of their field.
+///
+/// Arrays are added as multiple values of the same field, null values are skipped.
+fn json_to_pairs(
+    schema: &Schema,
+    object: &Map<String, JsonValue>,
+    position: usize,
+) -> (Vec<(String, Value)>, Vec<DocumentError>) {
+    let mut pairs = vec![];
+    let mut errors = vec![];
+    for (field_name, json_value) in object {
+        let field = match schema.get_field(field_name) {
+            Some(field) => field,
+            None => {
+                let message = format!("Field not in schema: {}", field_name);
+                errors.push(DocumentError::new(
+                    position,
+                    field_name,
+                    DocumentErrorKind::UnknownField,
+                    message,
+                ));
+                continue;
+            }
+        };
+        let field_type = schema.get_field_entry(field).field_type();
+        let json_values = match json_value {
+            JsonValue::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for json_value in json_values {
+            if json_value.is_null() {
+                continue;
+            }
+            match value_from_json(field_type, json_value) {
+                Ok(value) => pairs.push((field_name.clone(), value)),
+                Err(message) => errors.push(DocumentError::new(
+                    position,
+                    field_name,
+                    DocumentErrorKind::TypeMismatch,
+                    message,
+                )),
+            }
+        }
+    }
+    (pairs, errors)
+}
+
+fn build_document(
+    schema: &Schema,
+    doc: &[(String, Value)],
+    required_fields: &[String],
+    position: usize,
+) -> std::result::Result<Document, Vec<DocumentError>> {
+    let mut document = Document::default();
+    let mut errors = vec![];
+    for (field_name, value) in doc {
+        let field = match schema.get_field(field_name) {
+            Some(field) => field,
+            None => {
+                let message = format!("Field not in schema: {}", field_name);
+                errors.push(DocumentError::new(
+                    position,
+                    field_name,
+                    DocumentErrorKind::UnknownField,
+                    message,
+                ));
+                continue;
+            }
+        };
+        let field_type = schema.get_field_entry(field).field_type();
+        match convert_value(field_type, value) {
+            Some(value) => document.add_field_value(field, value),
+            None => {
+                let message = format!(
+                    "Expected a value of type {:?}, got {:?}",
+                    field_type.value_type(),
+                    value
+                );
+                errors.push(DocumentError::new(
+                    position,
+                    field_name,
+                    DocumentErrorKind::TypeMismatch,
+                    message,
+                ));
+            }
+        }
+    }
+    for field_name in required_fields {
+        if !doc.iter().any(|(name, _value)| name == field_name) {
+            let message = format!("Missing required field: {}", field_name);
+            errors.push(DocumentError::new(
+                position,
+                field_name,
+                DocumentErrorKind::MissingField,
+                message,
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(document)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parse a JSON value as the type of a field.
+///
+/// Dates are parsed from RFC 3339 strings or milliseconds since the unix epoch,
+/// bytes from base64 strings.
+fn value_from_json(
+    field_type: &FieldType,
+    json_value: JsonValue,
+) -> std::result::Result<Value, String> {
+    match (field_type, &json_value) {
+        (FieldType::Date(_), JsonValue::Number(number)) => number
+            .as_i64()
+            .and_then(date_from_millis)
+            .map(Value::Date)
+            .ok_or_else(|| format!("Invalid timestamp, expected whole seconds: {}", number)),
+        (FieldType::Date(_), JsonValue::String(text)) => date_from_rfc3339(text)
+            .map(Value::Date)
+            .ok_or_else(|| format!("Invalid RFC 3339 date, expected whole seconds: {}", text)),
+        _ => field_type
+            .value_from_json(json_value)
+            .map_err(|err| err.to_string()),
+    }
+}
+
+/// Dates are stored by tantivy in whole seconds. Dates with milliseconds are rejected,
+/// so that they are not truncated silently.
+fn date_from_millis(millis: i64) -> Option<DateTime> {
+    if millis % 1000 != 0 {
+        return None;
+    }
+    let date = OffsetDateTime::from_unix_timestamp(millis / 1000).ok()?;
+    Some(DateTime::from_utc(date))
+}
+
+/// As `date_from_millis`, dates with fractions of seconds are rejected.
+fn date_from_rfc3339(text: &str) -> Option<DateTime> {
+    let date = OffsetDateTime::parse(text, &Rfc3339).ok()?;
+    if date.nanosecond() != 0 {
+        return None;
+    }
+    Some(DateTime::from_utc(date))
+}
+
+/// Convert a value to the type of a field, if this is possible without loss.
+///
+/// Dates can be given as RFC 3339 strings or as milliseconds since the unix epoch,
+/// bytes as base64 strings. tantivy keeps dates in whole seconds, so dates with
+/// fractions of seconds can't be converted.
+pub fn convert_value(field_type: &FieldType, value: &Value) -> Option<Value> {
+    let value = match (field_type, value) {
+        (FieldType::Str(_), Value::Str(_)) => value.clone(),
+        (FieldType::Str(_), Value::PreTokStr(_)) => value.clone(),
+        (FieldType::U64(_), Value::U64(_)) => value.clone(),
+        (FieldType::U64(_), Value::I64(val)) if *val >= 0 => Value::U64(*val as u64),
+        (FieldType::I64(_), Value::I64(_)) => value.clone(),
+        (FieldType::I64(_), Value::U64(val)) if *val <= i64::MAX as u64 => Value::I64(*val as i64),
+        (FieldType::F64(_), Value::F64(_)) => value.clone(),
+        (FieldType::F64(_), Value::U64(val)) => Value::F64(*val as f64),
+        (FieldType::F64(_), Value::I64(val)) => Value::F64(*val as f64),
+        (FieldType::Date(_), Value::Date(_)) => value.clone(),
+        (FieldType::Date(_), Value::Str(text)) => Value::Date(date_from_rfc3339(text)?),
+        (FieldType::Date(_), Value::I64(millis)) => Value::Date(date_from_millis(*millis)?),
+        (FieldType::Date(_), Value::U64(millis)) => {
+            Value::Date(date_from_millis(i64::try_from(*millis).ok()?)?)
+        }
+        (FieldType::Facet(_), Value::Facet(_)) => value.clone(),
+        (FieldType::Facet(_), Value::Str(text)) => Value::Facet(Facet::from_text(text).ok()?),
+        (FieldType::Bytes(_), Value::Bytes(_)) => value.clone(),
+        (FieldType::Bytes(_), Value::Str(text)) => Value::Bytes(base64::decode(text).ok()?),
+        (FieldType::JsonObject(_), Value::JsonObject(_)) => value.clone(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Convert a stored document to a named document for clients.
+///
+/// If `fields` is set, only these fields are included.
+pub fn to_named_doc(
+    schema: &Schema,
+    doc: &Document,
+    fields: Option<&[String]>,
+) -> NamedFieldDocument {
+    let fields = match fields {
+        Some(fields) => fields,
+        None => return schema.to_named_doc(doc),
+    };
+    let mut field_map = BTreeMap::new();
+    for field_value in doc.field_values() {
+        let field_name = schema.get_field_name(field_value.field());
+        if fields.iter().any(|name| name == field_name) {
+            field_map
+                .entry(field_name.to_string())
+                .or_insert_with(Vec::new)
+                .push(field_value.value().clone());
+        }
+    }
+    NamedFieldDocument(field_map)
+}
+
+/// Convert a stored value to JSON for clients.
+///
+/// Dates are returned as RFC 3339 strings, bytes as base64 strings and facets as paths.
+pub fn value_to_json(value: &Value) -> JsonValue {
+    match value {
+        Value::Str(text) => JsonValue::from(text.as_str()),
+        Value::PreTokStr(tokenized) => JsonValue::from(tokenized.text.as_str()),
+        Value::U64(value) => JsonValue::from(*value),
+        Value::I64(value) => JsonValue::from(*value),
+        Value::F64(value) => JsonValue::from(*value),
+        Value::Date(date) => match date.into_utc().format(&Rfc3339) {
+            Ok(text) => JsonValue::from(text),
+            Err(_) => JsonValue::Null,
+        },
+        Value::Facet(facet) => JsonValue::from(facet.to_string()),
+        Value::Bytes(bytes) => JsonValue::from(base64::encode(bytes)),
+        Value::JsonObject(object) => JsonValue::Object(object.clone()),
+    }
+}
+
+/// A document as sent to clients, with the values of each field as JSON.
+pub type JsonDocument = BTreeMap<String, Vec<JsonValue>>;
+
+pub fn named_doc_to_json(doc: NamedFieldDocument) -> JsonDocument {
+    doc.0
+        .into_iter()
+        .map(|(field_name, values)| (field_name, values.iter().map(value_to_json).collect()))
+        .collect()
+}
+
+/// A document as sent to clients by the Toshi API: Fields with a single value
+/// have the value itself, fields with several values an array.
+pub fn flat_doc_to_json(doc: NamedFieldDocument) -> BTreeMap<String, JsonValue> {
+    named_doc_to_json(doc)
+        .into_iter()
+        .map(|(field_name, mut values)| {
+            let value = if values.len() == 1 {
+                values.remove(0)
+            } else {
+                JsonValue::Array(values)
+            };
+            (field_name, value)
+        })
+        .collect()
+}
+
+/// Get a field that can be used to select documents by term.
+pub fn get_term_field(schema: &Schema, field_name: &str) -> Result<Field> {
+    let field = schema
+        .get_field(field_name)
+        .ok_or_else(|| TantivyError::InvalidArgument(format!("Field not found: {}", field_name)))?;
+    if !schema.get_field_entry(field).is_indexed() {
+        return Err(TantivyError::InvalidArgument(format!(
+            "Field is not indexed: {}",
+            field_name
+        )));
+    }
+    Ok(field)
+}
+
+/// Get a field by which documents are deleted or updated.
+///
+/// Text fields have to use the `raw` tokenizer: values are matched as a single term,
+/// so they would never match the tokens of other tokenizers.
+pub fn get_key_field(schema: &Schema, field_name: &str) -> Result<Field> {
+    let field = get_term_field(schema, field_name)?;
+    if let FieldType::Str(options) = schema.get_field_entry(field).field_type() {
+        let tokenizer = options
+            .get_indexing_options()
+            .map(|indexing| indexing.tokenizer());
+        if tokenizer != Some("raw") {
+            return Err(TantivyError::InvalidArgument(format!(
+                "Field is tokenized and cannot identify documents, use the raw tokenizer: {}",
+                field_name
+            )));
+        }
+    }
+    Ok(field)
+}
+
+/// Build a term for a field from a value, converting the value to the type of the field.
+pub fn term_for_value(schema: &Schema, field: Field, value: &Value) -> Result<Term> {
+    let term = match convert_value(schema.get_field_entry(field).field_type(), value) {
+        Some(Value::Str(text)) => Term::from_field_text(field, &text),
+        Some(Value::U64(val)) => Term::from_field_u64(field, val),
+        Some(Value::I64(val)) => Term::from_field_i64(field, val),
+        Some(Value::F64(val)) => Term::from_field_f64(field, val),
+        Some(Value::Date(val)) => Term::from_field_date(field, val),
+        Some(Value::Facet(facet)) => Term::from_facet(field, &facet),
+        Some(Value::Bytes(bytes)) => Term::from_field_bytes(field, &bytes),
+        _ => {
+            return Err(TantivyError::InvalidArgument(format!(
+                "Cannot use value {:?} as term for field {}",
+                value,
+                schema.get_field_name(field)
+            )))
+        }
+    };
+    Ok(term)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::Interrupt;
+    use crate::fixture::Fixture;
+    use crate::options::{IndexOptions, QueryOptions};
+    use serde_json::json;
+    use tantivy::schema::{INDEXED, STORED, STRING};
+
+    #[test]
+    fn validate_documents() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        schema_builder.add_u64_field("size", INDEXED | STORED);
+        let options = IndexOptions {
+            required_fields: vec!["id".to_string()],
+            ..Default::default()
+        };
+        let fixture = Fixture::with_options(schema_builder.build(), options);
+        let handle = &fixture.handle;
+
+        let docs = vec![
+            DocumentInput::Pairs(vec![
+                ("id".to_string(), Value::Str("1".to_string())),
+                ("size".to_string(), Value::U64(10)),
+            ]),
+            DocumentInput::Pairs(vec![
+                ("id".to_string(), Value::Str("2".to_string())),
+                ("size".to_string(), Value::Str("big".to_string())),
+                ("color".to_string(), Value::Str("red".to_string())),
+            ]),
+            DocumentInput::Pairs(vec![("size".to_string(), Value::U64(20))]),
+        ];
+
+        let (added, errors) = handle
+            .add_documents(&docs, Some(true), OnError::Reject)
+            .unwrap();
+        assert_eq!(added, 0);
+        let kinds: Vec<(usize, DocumentErrorKind)> = errors
+            .iter()
+            .map(|error| (error.document, error.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, DocumentErrorKind::TypeMismatch),
+                (1, DocumentErrorKind::UnknownField),
+                (2, DocumentErrorKind::MissingField),
+            ]
+        );
+        assert_eq!(
+            handle
+                .query("*", &QueryOptions::default(), &Interrupt::none())
+                .unwrap()
+                .hits
+                .len(),
+            0
+        );
+
+        let (added, errors) = handle
+            .add_documents(&docs, Some(true), OnError::Skip)
+            .unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            handle
+                .query("*", &QueryOptions::default(), &Interrupt::none())
+                .unwrap()
+                .hits
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn add_json_documents() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        schema_builder.add_text_field("tags", STRING | STORED);
+        schema_builder.add_u64_field("size", INDEXED | STORED);
+        let fixture = Fixture::new(schema_builder.build());
+        let handle = &fixture.handle;
+
+        let docs: Vec<DocumentInput> = serde_json::from_value(serde_json::json!([
+            { "id": "1", "tags": ["red", "blue"], "size": 10 },
+            { "id": "2", "tags": "red", "size": "big" }
+        ]))
+        .unwrap();
+        let (added, errors) = handle
+            .add_documents(&docs, Some(true), OnError::Skip)
+            .unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].document, 1);
+        assert_eq!(errors[0].kind, DocumentErrorKind::TypeMismatch);
+
+        assert_eq!(
+            handle
+                .query("tags:blue", &QueryOptions::default(), &Interrupt::none())
+                .unwrap()
+                .hits
+                .len(),
+            1
+        );
+        let results = handle
+            .query("tags:red", &QueryOptions::default(), &Interrupt::none())
+            .unwrap();
+        assert_eq!(results.hits.len(), 1);
+        assert_eq!(results.hits[0].doc.0["tags"].len(), 2);
+    }
+
+    #[test]
+    fn convert_typed_values() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        schema_builder.add_date_field("created", INDEXED | STORED);
+        schema_builder.add_bytes_field("data", STORED);
+        schema_builder.add_json_field("meta", STORED);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(json!([
+            { "id": "a", "created": "2021-05-01T12:00:00Z", "data": "aGVsbG8=", "meta": { "x": 1 } },
+            { "id": "b", "created": 1619870400000i64 },
+            [["id", "c"], ["created", "2021-05-02T00:00:00+02:00"], ["data", "aGk="]],
+        ]));
+
+        let options = QueryOptions {
+            filter: serde_json::from_value(json!([
+                { "term": { "field": "created", "value": "2021-05-01T12:00:00Z" } },
+            ]))
+            .unwrap(),
+            ..Default::default()
+        };
+        let results = fixture
+            .handle
+            .query("*", &options, &Interrupt::none())
+            .unwrap();
+        let mut docs: Vec<_> = results
+            .hits
+            .into_iter()
+            .map(|hit| named_doc_to_json(hit.doc))
+            .collect();
+        docs.sort_by_key(|doc| doc["id"][0].as_str().unwrap().to_string());
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0]["created"][0], "2021-05-01T12:00:00Z");
+        assert_eq!(docs[0]["data"][0], "aGVsbG8=");
+        assert_eq!(docs[0]["meta"][0], json!({ "x": 1 }));
+        assert_eq!(docs[1]["created"][0], "2021-05-01T12:00:00Z");
+
+        let docs: Vec<DocumentInput> =
+            serde_json::from_value(json!([{ "id": "d", "created": "yesterday" }])).unwrap();
+        let (added, errors) = fixture
+            .handle
+            .add_documents(&docs, Some(true), OnError::Reject)
+            .unwrap();
+        assert_eq!(added, 0);
+        assert_eq!(errors[0].kind, DocumentErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn reject_fractions_of_seconds() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        schema_builder.add_date_field("created", INDEXED | STORED);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(json!([
+            { "id": "a", "created": 1619870400000i64 },
+            { "id": "b", "created": "2021-05-01T12:00:00.000Z" },
+            { "id": "c", "created": -1000i64 },
+        ]));
+
+        for created in &[json!(1619870400999i64), json!("2021-05-01T12:00:00.5Z")] {
+            let docs: Vec<DocumentInput> =
+                serde_json::from_value(json!([{ "id": "d", "created": created }])).unwrap();
+            let (added, errors) = fixture
+                .handle
+                .add_documents(&docs, Some(true), OnError::Reject)
+                .unwrap();
+            assert_eq!(added, 0);
+            assert_eq!(
+                errors[0].kind,
+                DocumentErrorKind::TypeMismatch,
+                "{}",
+                created
+            );
+        }
+
+        let query = |value: JsonValue| {
+            let options = QueryOptions {
+                filter: serde_json::from_value(json!([
+                    { "term": { "field": "created", "value": value } },
+                ]))
+                .unwrap(),
+                ..Default::default()
+            };
+            fixture.handle.query("*", &options, &Interrupt::none())
+        };
+        let results = query(json!(1619870400000i64)).unwrap();
+        assert_eq!(results.total_hits, 2);
+        for hit in results.hits {
+            let doc = named_doc_to_json(hit.doc);
+            assert_eq!(doc["created"][0], "2021-05-01T12:00:00Z");
+        }
+        assert_eq!(query(json!("1969-12-31T23:59:59Z")).unwrap().total_hits, 1);
+        assert!(query(json!(1619870400123i64)).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use tantivy::query::QueryParserError;
+use tantivy::TantivyError;
+
+/// Error codes
+///
+/// Stable codes that are sent to clients with every error, so that they can
+/// handle errors without matching on the message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    IndexNotFound,
+    QueryParseError,
+    SchemaInvalid,
+    InvalidRequest,
+    InvalidArgument,
+    MethodNotFound,
+    Cancelled,
+    Timeout,
+    Io,
+    Internal,
+}
+
+/// The error part of an RPC response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RpcError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl RpcError {
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn index_not_found(name: &str) -> Self {
+        RpcError::new(ErrorCode::IndexNotFound, "Index not found.")
+            .with_details(serde_json::json!({ "index": name }))
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        RpcError::new(ErrorCode::MethodNotFound, "Method not found.")
+            .with_details(serde_json::json!({ "method": method }))
+    }
+
+    pub fn cancelled() -> Self {
+        RpcError::new(ErrorCode::Cancelled, "Request was cancelled.")
+    }
+
+    pub fn timeout(timeout_ms: u64) -> Self {
+        RpcError::new(ErrorCode::Timeout, "Request timed out.")
+            .with_details(serde_json::json!({ "timeout_ms": timeout_ms }))
+    }
+
+    pub fn query_parse_error(query: &str, err: &QueryParserError) -> Self {
+        let mut err = RpcError::from(err);
+        if let Some(serde_json::Value::Object(details)) = &mut err.details {
+            details.insert("query".to_string(), query.into());
+        }
+        err
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<&TantivyError> for RpcError {
+    fn from(err: &TantivyError) -> Self {
+        let code = match err {
+            TantivyError::OpenDirectoryError(..)
+            | TantivyError::OpenReadError(..)
+            | TantivyError::OpenWriteError(..)
+            | TantivyError::IoError(..)
+            | TantivyError::LockFailure(..) => ErrorCode::Io,
+            TantivyError::InvalidArgument(..) => ErrorCode::InvalidArgument,
+            TantivyError::SchemaError(..) => ErrorCode::SchemaInvalid,
+            _ => ErrorCode::Internal,
+        };
+        RpcError::new(code, err)
+    }
+}
+
+/// The details of a query parse error have the `kind` of the error and, where the parser
+/// reports them, the `field` and `token` that could not be parsed.
+impl From<&QueryParserError> for RpcError {
+    fn from(err: &QueryParserError) -> Self {
+        let (kind, field, token) = match err {
+            QueryParserError::SyntaxError(query) => ("syntax_error", None, Some(query.as_str())),
+            QueryParserError::UnsupportedQuery(_) => ("unsupported_query", None, None),
+            QueryParserError::FieldDoesNotExist(field) => {
+                ("field_does_not_exist", Some(field.as_str()), None)
+            }
+            QueryParserError::ExpectedInt(_) => ("expected_int", None, None),
+            QueryParserError::ExpectedBase64(_) => ("expected_base64", None, None),
+            QueryParserError::ExpectedFloat(_) => ("expected_float", None, None),
+            QueryParserError::AllButQueryForbidden => ("all_but_query_forbidden", None, None),
+            QueryParserError::NoDefaultFieldDeclared => ("no_default_field_declared", None, None),
+            QueryParserError::FieldNotIndexed(field) => {
+                ("field_not_indexed", Some(field.as_str()), None)
+            }
+            QueryParserError::FieldDoesNotHavePositionsIndexed(field) => (
+                "field_does_not_have_positions_indexed",
+                Some(field.as_str()),
+                None,
+            ),
+            QueryParserError::UnknownTokenizer { field, .. } => {
+                ("unknown_tokenizer", Some(field.as_str()), None)
+            }
+            QueryParserError::RangeMustNotHavePhrase => ("range_must_not_have_phrase", None, None),
+            QueryParserError::DateFormatError(_) => ("date_format_error", None, None),
+            QueryParserError::FacetFormatError(_) => ("facet_format_error", None, None),
+        };
+        let mut details = serde_json::json!({ "kind": kind });
+        if let Some(field) = field {
+            details["field"] = field.into();
+        }
+        if let Some(token) = token {
+            details["token"] = token.into();
+        }
+        RpcError::new(
+            ErrorCode::QueryParseError,
+            format!("Invalid query: {}", err),
+        )
+        .with_details(details)
+    }
+}
+
+impl From<&toshi_types::Error> for RpcError {
+    fn from(err: &toshi_types::Error) -> Self {
+        match err {
+            // Toshi reports most invalid queries as query errors.
+            toshi_types::Error::QueryError(message) => {
+                RpcError::new(ErrorCode::InvalidArgument, message)
+            }
+            toshi_types::Error::UnknownIndexField(field) => RpcError::new(
+                ErrorCode::QueryParseError,
+                format!("Invalid query: {}", err),
+            )
+            .with_details(serde_json::json!({ "kind": "field_does_not_exist", "field": field })),
+            toshi_types::Error::UnknownIndex(name) => RpcError::index_not_found(name),
+            toshi_types::Error::IOError(_) => RpcError::new(ErrorCode::Io, err),
+            // The other errors are about search requests that Toshi cannot handle.
+            _ => RpcError::new(ErrorCode::InvalidArgument, err),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<RpcError>() {
+            err.clone()
+        } else if let Some(err) = err.downcast_ref::<TantivyError>() {
+            RpcError::from(err)
+        } else if let Some(err) = err.downcast_ref::<QueryParserError>() {
+            RpcError::from(err)
+        } else if let Some(err) = err.downcast_ref::<toshi_types::Error>() {
+            RpcError::from(err)
+        } else if let Some(err) = err.downcast_ref::<serde_json::Error>() {
+            RpcError::new(ErrorCode::InvalidRequest, err)
+        } else if let Some(err) = err.downcast_ref::<io::Error>() {
+            RpcError::new(ErrorCode::Io, err)
+        } else {
+            RpcError::new(ErrorCode::Internal, err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::Interrupt;
+    use crate::fixture::Fixture;
+    use crate::options::QueryOptions;
+    use crate::search::{search_index, ResultOptions, SearchOptions};
+    use tantivy::schema::{FacetOptions, Schema, FAST, STORED, TEXT};
+
+    #[test]
+    fn search_error_codes() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("body", TEXT | STORED);
+        schema_builder.add_u64_field("size", FAST);
+        schema_builder.add_facet_field("category", FacetOptions::default());
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([{ "body": "x", "size": 1, "category": "/a" }]));
+
+        let search = |search: serde_json::Value, options: serde_json::Value| {
+            let (options, result_options): (SearchOptions, ResultOptions) = (
+                serde_json::from_value(options.clone()).unwrap(),
+                serde_json::from_value(options).unwrap(),
+            );
+            let err = search_index(
+                &fixture.handle.index,
+                &fixture.handle.get_reader().unwrap(),
+                serde_json::from_value(search).unwrap(),
+                &options,
+                &result_options,
+                &Interrupt::none(),
+            )
+            .unwrap_err();
+            RpcError::from(err)
+        };
+        let raw = serde_json::json!({ "query": { "raw": "x" }, "limit": 10 });
+
+        let err = search(
+            serde_json::json!({ "query": { "raw": "body:(x" } }),
+            serde_json::json!({}),
+        );
+        assert_eq!(err.code, ErrorCode::QueryParseError);
+        assert_eq!(err.message, "Invalid query: Syntax Error: body:(x");
+        assert_eq!(
+            err.details,
+            Some(serde_json::json!({
+                "kind": "syntax_error",
+                "token": "body:(x",
+                "query": "body:(x",
+            }))
+        );
+        let err = search(
+            serde_json::json!({ "query": { "raw": "title:x" } }),
+            serde_json::json!({}),
+        );
+        assert_eq!(err.code, ErrorCode::QueryParseError);
+        assert_eq!(err.details.unwrap()["field"], "title");
+        let err = search(
+            serde_json::json!({ "query": { "term": { "title": "x" } } }),
+            serde_json::json!({}),
+        );
+        assert_eq!(err.code, ErrorCode::QueryParseError);
+        assert_eq!(err.details.unwrap()["field"], "title");
+
+        let invalid_options = [
+            serde_json::json!({ "cursor": "not a cursor" }),
+            serde_json::json!({ "sort": [{ "field": "body" }] }),
+            serde_json::json!({ "facets": [{ "field": "body" }] }),
+            serde_json::json!({ "aggregations": { "a": { "avg": { "field": "body" } } } }),
+        ];
+        for options in invalid_options {
+            let err = search(raw.clone(), options.clone());
+            assert_eq!(err.code, ErrorCode::InvalidArgument, "{}", options);
+        }
+    }
+
+    #[test]
+    fn query_error_codes() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("body", TEXT | STORED);
+        let fixture = Fixture::new(schema_builder.build());
+
+        let err = RpcError::from(
+            fixture
+                .handle
+                .query("body:(x", &QueryOptions::default(), &Interrupt::none())
+                .unwrap_err(),
+        );
+        assert_eq!(err.code, ErrorCode::QueryParseError);
+        assert_eq!(err.details.unwrap()["kind"], "syntax_error");
+
+        let options = QueryOptions {
+            cursor: Some("not a cursor".to_string()),
+            ..Default::default()
+        };
+        let err = RpcError::from(
+            fixture
+                .handle
+                .query("x", &options, &Interrupt::none())
+                .unwrap_err(),
+        );
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+
+        let err = RpcError::from(anyhow::Error::from(toshi_types::Error::QueryError(
+            "Bad query".to_string(),
+        )));
+        assert_eq!(err.code, ErrorCode::InvalidArgument);
+        assert_eq!(err.message, "Bad query");
+        let err = RpcError::from(anyhow::Error::from(toshi_types::Error::UnknownIndex(
+            "missing".to_string(),
+        )));
+        assert_eq!(err.code, ErrorCode::IndexNotFound);
+    }
+
+    #[test]
+    fn schema_error_codes() {
+        let err = crate::handles::parse_schema(serde_json::json!([
+            { "name": "address", "type": "ip", "options": { "stored": true } },
+        ]))
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::SchemaInvalid);
+        assert_eq!(err.details, Some(serde_json::json!({ "field": "address" })));
+
+        let err = crate::handles::parse_schema(serde_json::json!([{ "name": "x" }])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::SchemaInvalid);
+    }
+}
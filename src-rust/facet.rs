@@ -0,0 +1,207 @@
+use crate::filter::with_filters;
+use serde::{Deserialize, Serialize};
+use tantivy::collector::{FacetCollector, FacetCounts};
+use tantivy::query::{Query, TermQuery};
+use tantivy::schema::{Facet, Field, FieldType, IndexRecordOption, Schema, Term};
+use toshi_types::{Error, KeyValue};
+
+/// Facet counts to collect for the hits of a query, for one facet field.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FacetRequest {
+    pub field: String,
+    /// Facets to count the children of. Default: the `drill_down` facet, or the root.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Only return the children with the highest counts (default: all children).
+    pub top_k: Option<usize>,
+    /// Only match documents that have this facet or one of its descendants.
+    pub drill_down: Option<String>,
+}
+
+/// The counts of the children of a facet in the hits of a query.
+#[derive(Serialize, Debug)]
+pub struct FacetOutput {
+    pub field: String,
+    pub path: String,
+    pub counts: Vec<KeyValue<String, u64>>,
+}
+
+/// The facets of a field to count the children of.
+pub struct FacetCollection {
+    field: Field,
+    field_name: String,
+    paths: Vec<Facet>,
+    top_k: Option<usize>,
+}
+
+impl FacetCollection {
+    pub fn new(schema: &Schema, request: &FacetRequest) -> Result<Self, Error> {
+        let field = facet_field(schema, &request.field)?;
+        let mut paths = vec![];
+        for path in &request.paths {
+            paths.push(parse_facet(path)?);
+        }
+        if paths.is_empty() {
+            paths.push(match &request.drill_down {
+                Some(path) => parse_facet(path)?,
+                None => Facet::root(),
+            });
+        }
+        paths.sort();
+        paths.dedup();
+        // The facet collector panics on facets that contain each other.
+        for path in &paths {
+            if paths.iter().any(|other| path.is_prefix_of(other)) {
+                return Err(Error::QueryError(format!(
+                    "Facet paths of a field must not contain each other: {}",
+                    path
+                )));
+            }
+        }
+        Ok(FacetCollection {
+            field,
+            field_name: request.field.clone(),
+            paths,
+            top_k: request.top_k,
+        })
+    }
+
+    pub fn collector(&self) -> FacetCollector {
+        let mut collector = FacetCollector::for_field(self.field);
+        for path in &self.paths {
+            collector.add_facet(path.clone());
+        }
+        collector
+    }
+
+    /// The counts of the children of each requested facet.
+    pub fn outputs(&self, counts: &FacetCounts) -> Vec<FacetOutput> {
+        self.paths
+            .iter()
+            .map(|path| {
+                let children: Vec<(&Facet, u64)> = match self.top_k {
+                    Some(k) => counts.top_k(path.clone(), k),
+                    None => counts.get(path.clone()).collect(),
+                };
+                FacetOutput {
+                    field: self.field_name.clone(),
+                    path: path.to_string(),
+                    counts: children
+                        .into_iter()
+                        .map(|(facet, count)| KeyValue::new(facet.to_string(), count))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Restrict a query to the documents with the `drill_down` facets of the requests.
+///
+/// The facet terms don't change the scores of the hits.
+pub fn drill_down(
+    schema: &Schema,
+    requests: &[FacetRequest],
+    query: Box<dyn Query>,
+) -> Result<Box<dyn Query>, Error> {
+    let mut filters: Vec<Box<dyn Query>> = vec![];
+    for request in requests {
+        if let Some(path) = &request.drill_down {
+            let field = facet_field(schema, &request.field)?;
+            let term = Term::from_facet(field, &parse_facet(path)?);
+            filters.push(Box::new(TermQuery::new(term, IndexRecordOption::Basic)));
+        }
+    }
+    Ok(with_filters(query, filters))
+}
+
+fn facet_field(schema: &Schema, field_name: &str) -> Result<Field, Error> {
+    let field = schema
+        .get_field(field_name)
+        .ok_or_else(|| Error::QueryError(format!("Facet field not found: {}", field_name)))?;
+    match schema.get_field_entry(field).field_type() {
+        FieldType::Facet(_) => Ok(field),
+        _ => Err(Error::QueryError(format!(
+            "Not a facet field: {}",
+            field_name
+        ))),
+    }
+}
+
+fn parse_facet(path: &str) -> Result<Facet, Error> {
+    Facet::from_text(path).map_err(|_| Error::QueryError(format!("Invalid facet: {}", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixture;
+    use crate::search::ResultOptions;
+    use tantivy::schema::{FacetOptions, TEXT};
+
+    #[test]
+    fn search_facets() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("body", TEXT);
+        schema_builder.add_facet_field("category", FacetOptions::default());
+        schema_builder.add_facet_field("tag", FacetOptions::default());
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([
+            { "body": "x", "category": "/books/fiction", "tag": "/new" },
+            { "body": "x", "category": "/books/poetry", "tag": "/new" },
+            { "body": "x", "category": "/books/fiction", "tag": "/new" },
+            { "body": "x", "category": "/music/jazz", "tag": "/old" },
+        ]));
+
+        let search = |facets: serde_json::Value| {
+            let result_options = ResultOptions {
+                facets: serde_json::from_value(facets).unwrap(),
+                ..Default::default()
+            };
+            let search = serde_json::json!({ "query": { "raw": "x" }, "limit": 10 });
+            fixture.search(search, &result_options).map(|results| {
+                (
+                    results.total_hits,
+                    serde_json::to_value(results.facet_counts).unwrap(),
+                )
+            })
+        };
+
+        let (total_hits, facet_counts) = search(serde_json::json!([
+            { "field": "category", "paths": ["/books", "/music"] },
+            { "field": "tag", "top_k": 1 },
+        ]))
+        .unwrap();
+        assert_eq!(total_hits, 4);
+        assert_eq!(
+            facet_counts,
+            serde_json::json!([
+                { "field": "category", "path": "/books", "counts": [
+                    { "key": "/books/fiction", "value": 2 },
+                    { "key": "/books/poetry", "value": 1 },
+                ] },
+                { "field": "category", "path": "/music", "counts": [
+                    { "key": "/music/jazz", "value": 1 },
+                ] },
+                { "field": "tag", "path": "/", "counts": [
+                    { "key": "/new", "value": 3 },
+                ] },
+            ])
+        );
+
+        // Drill down into a category, counting its children.
+        let (total_hits, facet_counts) = search(serde_json::json!([
+            { "field": "category", "drill_down": "/books" },
+        ]))
+        .unwrap();
+        assert_eq!(total_hits, 3);
+        assert_eq!(facet_counts[0]["path"], "/books");
+        assert_eq!(facet_counts[0]["counts"].as_array().unwrap().len(), 2);
+
+        assert!(search(serde_json::json!([{ "field": "body" }])).is_err());
+        assert!(search(
+            serde_json::json!([{ "field": "category", "paths": ["/books", "/books/fiction"] }])
+        )
+        .is_err());
+    }
+}
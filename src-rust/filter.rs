@@ -0,0 +1,205 @@
+use crate::document::{get_term_field, term_for_value};
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+use tantivy::query::{BooleanQuery, BoostQuery, Occur, Query, RangeQuery, TermQuery};
+use tantivy::schema::{Facet, IndexRecordOption, Schema, Term, Value};
+use tantivy::{Result, TantivyError};
+
+/// A constraint on the hits of a query that does not change their scores.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)]
+pub enum Filter {
+    /// Documents that contain a term in a field.
+    Term { field: String, value: Value },
+    /// Documents with a value in a field within the bounds.
+    Range {
+        field: String,
+        gt: Option<Value>,
+        gte: Option<Value>,
+        lt: Option<Value>,
+        lte: Option<Value>,
+    },
+    /// Documents that have a facet or one of its descendants.
+    Facet { field: String, path: String },
+    /// Documents that have any value in a field.
+    Exists { field: String },
+}
+
+impl Filter {
+    pub fn to_query(&self, schema: &Schema) -> Result<Box<dyn Query>> {
+        let query: Box<dyn Query> = match self {
+            Filter::Term { field, value } => {
+                let field = get_term_field(schema, field)?;
+                let term = term_for_value(schema, field, value)?;
+                Box::new(TermQuery::new(term, IndexRecordOption::Basic))
+            }
+            Filter::Range {
+                field,
+                gt,
+                gte,
+                lt,
+                lte,
+            } => {
+                let field = get_term_field(schema, field)?;
+                let bound =
+                    |exclusive: &Option<Value>, inclusive: &Option<Value>| -> Result<Bound<Term>> {
+                        Ok(match (exclusive, inclusive) {
+                            (Some(_), Some(_)) => {
+                                return Err(TantivyError::InvalidArgument(
+                                    "A range can't have both an exclusive and an inclusive bound \
+                                 on the same side (gt and gte, or lt and lte)"
+                                        .to_string(),
+                                ))
+                            }
+                            (Some(value), None) => {
+                                Bound::Excluded(term_for_value(schema, field, value)?)
+                            }
+                            (None, Some(value)) => {
+                                Bound::Included(term_for_value(schema, field, value)?)
+                            }
+                            (None, None) => Bound::Unbounded,
+                        })
+                    };
+                let lower = bound(gt, gte)?;
+                let upper = bound(lt, lte)?;
+                let value_type = schema.get_field_entry(field).field_type().value_type();
+                Box::new(RangeQuery::new_term_bounds(
+                    field, value_type, &lower, &upper,
+                ))
+            }
+            Filter::Facet { field, path } => {
+                let field = get_term_field(schema, field)?;
+                let facet = Facet::from_text(path).map_err(|_| {
+                    TantivyError::InvalidArgument(format!("Invalid facet: {}", path))
+                })?;
+                let term = Term::from_facet(field, &facet);
+                Box::new(TermQuery::new(term, IndexRecordOption::Basic))
+            }
+            Filter::Exists { field } => {
+                let field = get_term_field(schema, field)?;
+                let value_type = schema.get_field_entry(field).field_type().value_type();
+                Box::new(RangeQuery::new_term_bounds(
+                    field,
+                    value_type,
+                    &Bound::Unbounded,
+                    &Bound::Unbounded,
+                ))
+            }
+        };
+        Ok(query)
+    }
+}
+
+/// Restrict a query to the documents that match all filters.
+///
+/// The filters are combined with a boost of 0, so that they don't change the scores.
+pub fn with_filters(query: Box<dyn Query>, filters: Vec<Box<dyn Query>>) -> Box<dyn Query> {
+    if filters.is_empty() {
+        return query;
+    }
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, query)];
+    for filter in filters {
+        clauses.push((Occur::Must, Box::new(BoostQuery::new(filter, 0.0))));
+    }
+    Box::new(BooleanQuery::new(clauses))
+}
+
+/// Restrict a query to the documents that match all filters of a request.
+pub fn apply_filters(
+    schema: &Schema,
+    query: Box<dyn Query>,
+    filters: &[Filter],
+) -> Result<Box<dyn Query>> {
+    let mut filter_queries = vec![];
+    for filter in filters {
+        filter_queries.push(filter.to_query(schema)?);
+    }
+    Ok(with_filters(query, filter_queries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::Interrupt;
+    use crate::error::{ErrorCode, RpcError};
+    use crate::fixture::Fixture;
+    use crate::index::QueryResults;
+    use crate::options::QueryOptions;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tantivy::schema::{FacetOptions, INDEXED, STORED, STRING, TEXT};
+
+    fn scores(results: &QueryResults) -> HashMap<String, f32> {
+        results
+            .hits
+            .iter()
+            .map(|hit| match &hit.doc.0["id"][0] {
+                Value::Str(id) => (id.clone(), hit.score),
+                _ => panic!("id is not a string"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_filters() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        schema_builder.add_text_field("body", TEXT);
+        schema_builder.add_text_field("type", STRING);
+        schema_builder.add_u64_field("size", INDEXED);
+        schema_builder.add_facet_field("category", FacetOptions::default());
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(json!([
+            { "id": "a", "body": "hello world", "type": "book", "size": 10, "category": "/books/fiction" },
+            { "id": "b", "body": "hello hello", "type": "song", "size": 20, "category": "/music" },
+            { "id": "c", "body": "hello", "type": "book", "category": "/books/poetry" },
+        ]));
+
+        let query = |filter: serde_json::Value| {
+            let options = QueryOptions {
+                filter: serde_json::from_value(filter).unwrap(),
+                ..Default::default()
+            };
+            fixture.handle.query("hello", &options, &Interrupt::none())
+        };
+
+        let unfiltered = scores(&query(json!([])).unwrap());
+        assert_eq!(unfiltered.len(), 3);
+        let filtered =
+            scores(&query(json!([{ "term": { "field": "type", "value": "book" } }])).unwrap());
+        assert_eq!(filtered.len(), 2);
+        assert_eq!(filtered["a"], unfiltered["a"]);
+        assert_eq!(filtered["c"], unfiltered["c"]);
+
+        let total_hits = |filter: serde_json::Value| query(filter).unwrap().total_hits;
+        assert_eq!(
+            total_hits(json!([{ "range": { "field": "size", "gt": 10 } }])),
+            1
+        );
+        assert_eq!(
+            total_hits(json!([{ "range": { "field": "size", "gte": 10, "lt": 20 } }])),
+            1
+        );
+        assert_eq!(total_hits(json!([{ "exists": { "field": "size" } }])), 2);
+        assert_eq!(
+            total_hits(json!([{ "facet": { "field": "category", "path": "/books" } }])),
+            2
+        );
+        assert_eq!(
+            total_hits(json!([
+                { "facet": { "field": "category", "path": "/books" } },
+                { "exists": { "field": "size" } },
+            ])),
+            1
+        );
+        assert!(query(json!([{ "exists": { "field": "missing" } }])).is_err());
+        for range in &[
+            json!({ "field": "size", "gt": 10, "gte": 20 }),
+            json!({ "field": "size", "lt": 10, "lte": 20 }),
+        ] {
+            let err = query(json!([{ "range": range }])).unwrap_err();
+            assert_eq!(RpcError::from(err).code, ErrorCode::InvalidArgument);
+        }
+    }
+}
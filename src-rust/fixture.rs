@@ -0,0 +1,77 @@
+//! Setup shared by the tests of several modules.
+
+use crate::collector::Interrupt;
+use crate::document::{DocumentInput, OnError};
+use crate::index::{IndexCatalog, IndexHandle, QueryResults};
+use crate::options::IndexOptions;
+use crate::search::{search_index, ResultOptions, SearchOptions, SearchResults};
+use std::sync::Arc;
+use tantivy::schema::{Schema, Value};
+use tempdir::TempDir;
+
+/// An index catalog in a temporary directory, with a RAM index `testindex`.
+pub struct Fixture {
+    pub handle: Arc<IndexHandle>,
+    // Kept alive for the handle; the directory is removed when the fixture is dropped.
+    _catalog: IndexCatalog,
+    _dir: TempDir,
+}
+
+impl Fixture {
+    pub fn new(schema: Schema) -> Self {
+        Fixture::with_options(schema, IndexOptions::default())
+    }
+
+    pub fn with_options(schema: Schema, options: IndexOptions) -> Self {
+        let dir = TempDir::new("test").unwrap();
+        let catalog = IndexCatalog::new(dir.path().to_path_buf()).unwrap();
+        catalog
+            .create_ram_index("testindex".to_string(), schema, options)
+            .unwrap();
+        let handle = catalog.get_index("testindex").unwrap();
+        Fixture {
+            handle,
+            _catalog: catalog,
+            _dir: dir,
+        }
+    }
+
+    /// Add and commit documents, given as a JSON array. All documents have to be valid.
+    pub fn add(&self, docs: serde_json::Value) {
+        let docs: Vec<DocumentInput> = serde_json::from_value(docs).unwrap();
+        let (added, errors) = self
+            .handle
+            .add_documents(&docs, Some(true), OnError::Reject)
+            .unwrap();
+        assert!(errors.is_empty(), "Invalid documents: {:?}", errors);
+        assert_eq!(added, docs.len());
+    }
+
+    /// Search with a request in the JSON format of Toshi.
+    pub fn search(
+        &self,
+        search: serde_json::Value,
+        result_options: &ResultOptions,
+    ) -> anyhow::Result<SearchResults> {
+        search_index(
+            &self.handle.index,
+            &self.handle.get_reader().unwrap(),
+            serde_json::from_value(search).unwrap(),
+            &SearchOptions::default(),
+            result_options,
+            &Interrupt::none(),
+        )
+    }
+}
+
+/// The `id` fields of the hits of a query, in the order of the hits.
+pub fn ids(results: &QueryResults) -> Vec<String> {
+    results
+        .hits
+        .iter()
+        .map(|hit| match &hit.doc.0["id"][0] {
+            Value::Str(id) => id.clone(),
+            _ => panic!("id is not a string"),
+        })
+        .collect()
+}
@@ -0,0 +1,170 @@
+use crate::error::{ErrorCode, RpcError};
+use serde::{Deserialize, Serialize};
+use tantivy::query::{BooleanQuery, BoostQuery, EmptyQuery, FuzzyTermQuery, Occur, Query};
+use tantivy::schema::{Field, FieldType, Term};
+use tantivy::{Index, Score};
+
+/// The largest edit distance that tantivy supports for fuzzy queries.
+const MAX_EDIT_DISTANCE: u8 = 2;
+
+/// How the string of a query is turned into a tantivy query.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryMode {
+    /// Parse the query with the tantivy query grammar.
+    #[default]
+    Syntax,
+    /// Search for the words of the query, and for words that start with its last word
+    /// (for search as you type). Words match with up to `edit_distance` typos (default 0).
+    Prefix,
+    /// Search for the words of the query with up to `edit_distance` typos (default 1).
+    Fuzzy,
+}
+
+impl QueryMode {
+    fn default_edit_distance(&self) -> u8 {
+        match self {
+            QueryMode::Fuzzy => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Build a query for the `prefix` and `fuzzy` modes.
+///
+/// The query is split into words at whitespace, and each word is tokenized with the
+/// tokenizer of each of the text fields in `fields`. A word matches if all its tokens
+/// match in one of the fields. In the `prefix` mode, the last token of the query
+/// also matches longer terms that start with it. Words are combined with AND if `conjunction` is set,
+/// else with OR, as by the query parser.
+pub fn build_query(
+    index: &Index,
+    fields: &[(Field, Score)],
+    query: &str,
+    mode: QueryMode,
+    edit_distance: Option<u8>,
+    conjunction: bool,
+) -> anyhow::Result<Box<dyn Query>> {
+    let distance = edit_distance.unwrap_or_else(|| mode.default_edit_distance());
+    if distance > MAX_EDIT_DISTANCE {
+        let message = format!("The max edit distance is {}.", MAX_EDIT_DISTANCE);
+        let error = RpcError::new(ErrorCode::InvalidArgument, message)
+            .with_details(serde_json::json!({ "edit_distance": distance }));
+        return Err(error.into());
+    }
+    let schema = index.schema();
+    let text_fields: Vec<(Field, Score)> = fields
+        .iter()
+        .copied()
+        .filter(|(field, _boost)| {
+            let entry = schema.get_field_entry(*field);
+            entry.is_indexed() && matches!(entry.field_type(), FieldType::Str(_))
+        })
+        .collect();
+
+    let words: Vec<&str> = query.split_whitespace().collect();
+    let occur = if conjunction {
+        Occur::Must
+    } else {
+        Occur::Should
+    };
+    let mut word_queries = vec![];
+    for (i, word) in words.iter().enumerate() {
+        let prefix = mode == QueryMode::Prefix && i == words.len() - 1;
+        let mut field_queries: Vec<(Occur, Box<dyn Query>)> = vec![];
+        for (field, boost) in &text_fields {
+            let mut tokens = vec![];
+            index
+                .tokenizer_for_field(*field)?
+                .token_stream(word)
+                .process(&mut |token| tokens.push(token.text.clone()));
+            if tokens.is_empty() {
+                continue;
+            }
+            let last = tokens.len() - 1;
+            let token_queries: Vec<(Occur, Box<dyn Query>)> = tokens
+                .iter()
+                .enumerate()
+                .map(|(j, token)| {
+                    let term = Term::from_field_text(*field, token);
+                    let query = if prefix && j == last {
+                        FuzzyTermQuery::new_prefix(term, distance, true)
+                    } else {
+                        FuzzyTermQuery::new(term, distance, true)
+                    };
+                    (Occur::Must, Box::new(query) as Box<dyn Query>)
+                })
+                .collect();
+            let field_query: Box<dyn Query> = Box::new(BooleanQuery::new(token_queries));
+            let field_query = if (*boost - 1.0).abs() > f32::EPSILON {
+                Box::new(BoostQuery::new(field_query, *boost))
+            } else {
+                field_query
+            };
+            field_queries.push((Occur::Should, field_query));
+        }
+        if !field_queries.is_empty() {
+            word_queries.push((
+                occur,
+                Box::new(BooleanQuery::new(field_queries)) as Box<dyn Query>,
+            ));
+        }
+    }
+    if word_queries.is_empty() {
+        return Ok(Box::new(EmptyQuery));
+    }
+    Ok(Box::new(BooleanQuery::new(word_queries)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::Interrupt;
+    use crate::fixture::Fixture;
+    use crate::options::QueryOptions;
+    use tantivy::schema::{Schema, INDEXED, STORED, TEXT};
+
+    #[test]
+    fn query_prefix_and_fuzzy() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("title", TEXT | STORED);
+        schema_builder.add_u64_field("size", INDEXED | STORED);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([
+            { "title": "hello world", "size": 1 },
+            { "title": "help wanted", "size": 2 },
+        ]));
+
+        let total_hits = |query: &str, options: &QueryOptions| {
+            fixture
+                .handle
+                .query(query, options, &Interrupt::none())
+                .unwrap()
+                .total_hits
+        };
+        let prefix = QueryOptions {
+            mode: QueryMode::Prefix,
+            ..Default::default()
+        };
+        assert_eq!(total_hits("hel", &prefix), 2);
+        assert_eq!(total_hits("hello wor", &prefix), 1);
+        assert_eq!(total_hits("wor hello", &prefix), 1);
+
+        let fuzzy = QueryOptions {
+            mode: QueryMode::Fuzzy,
+            conjunction: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(total_hits("helo wrld", &fuzzy), 1);
+        assert_eq!(total_hits("helo wrld", &QueryOptions::default()), 0);
+
+        let too_fuzzy = QueryOptions {
+            edit_distance: Some(3),
+            ..fuzzy
+        };
+        assert!(fixture
+            .handle
+            .query("hello", &too_fuzzy, &Interrupt::none())
+            .is_err());
+    }
+}
@@ -1,10 +1,16 @@
-use crate::index::{IndexCatalog, SegmentInfo};
+use crate::collector::{HitAddress, Interrupt};
+use crate::document::{named_doc_to_json, DocumentError, DocumentInput, JsonDocument, OnError};
+use crate::error::{ErrorCode, RpcError};
+use crate::index::{IndexCatalog, IndexInfo, QueryHit, QueryResults, SegmentInfo};
+use crate::options::{IndexOptions, QueryOptions};
 use crate::rpc::Request;
+use crate::snippet::SnippetOutput;
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
-use serde_json;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use tantivy::schema::{NamedFieldDocument, Value};
+use std::time::Instant;
+use tantivy::schema::Value;
 
 /// Handles
 ///
@@ -16,8 +22,12 @@ use tantivy::schema::{NamedFieldDocument, Value};
 #[serde(untagged)]
 pub enum Res {
     Empty(Empty),
+    AddDocumentsResponse(AddDocumentsResponse),
     QueryResponse(QueryResponse),
     QueryMultiResponse(QueryMultiResponse),
+    QueryMergedResponse(QueryMergedResponse),
+    IndexInfo(IndexInfo),
+    IndexList(Vec<IndexInfo>),
     Bool(bool),
     // HACK: Return serde_json::Value!
     Json(String),
@@ -37,95 +47,254 @@ pub struct CreateIndex {
     pub name: String,
     // This is later casted into tantivy::schema::Schema
     pub schema: serde_json::Value,
+    pub options: Option<IndexOptions>,
 }
 
-pub fn create_index(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
+pub fn create_index(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
     let req: CreateIndex = request.message()?;
-    let schema_json = serde_json::to_string(&req.schema)?;
-    let schema: tantivy::schema::Schema = serde_json::from_str(&schema_json)?;
-    catalog.create_index(req.name.clone(), schema)?;
+    let schema = parse_schema(req.schema)?;
+    catalog.create_index(req.name.clone(), schema, req.options.unwrap_or_default())?;
     Ok(Res::empty())
 }
 
-pub fn delete_index(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
+pub fn parse_schema(schema: serde_json::Value) -> Result<tantivy::schema::Schema, RpcError> {
+    // Newer tantivy versions have IP address fields, explain how to store them with this one.
+    if let Some(fields) = schema.as_array() {
+        for field in fields {
+            if matches!(field["type"].as_str(), Some("ip") | Some("ip_addr")) {
+                let message = format!(
+                    "IP address fields are not supported, use a text field with the raw tokenizer: {}",
+                    field["name"]
+                );
+                return Err(RpcError::new(ErrorCode::SchemaInvalid, message)
+                    .with_details(serde_json::json!({ "field": field["name"] })));
+            }
+        }
+    }
+    serde_json::from_value(schema).map_err(|err| RpcError::new(ErrorCode::SchemaInvalid, err))
+}
+
+pub fn delete_index(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
     let name: String = request.message()?;
     catalog.delete_index(name)?;
     Ok(Res::empty())
 }
 
-pub fn create_ram_index(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
+pub fn create_ram_index(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
     let req: CreateIndex = request.message()?;
-    let schema_json = serde_json::to_string(&req.schema)?;
-    let schema: tantivy::schema::Schema = serde_json::from_str(&schema_json)?;
-    catalog.create_ram_index(req.name.clone(), schema)?;
+    let schema = parse_schema(req.schema)?;
+    catalog.create_ram_index(req.name.clone(), schema, req.options.unwrap_or_default())?;
     Ok(Res::empty())
 }
 
-pub fn index_exists(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetIndexOptions {
+    pub index: String,
+    pub options: IndexOptions,
+}
+
+pub fn set_index_options(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let req: SetIndexOptions = request.message()?;
+    let handle = catalog.get_index(&req.index)?;
+    handle.set_options(req.options)?;
+    Ok(Res::empty())
+}
+
+pub fn index_exists(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
     let name: String = request.message()?;
-    let has = match catalog.get_index(&name) {
-        Ok(_) => true,
-        Err(_) => false,
-    };
+    let has = catalog.get_index(&name).is_ok();
     Ok(Res::Bool(has))
 }
 
+pub fn get_schema(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let name: String = request.message()?;
+    Ok(Res::IndexInfo(catalog.index_info(&name)?))
+}
+
+pub fn list_indexes(catalog: &IndexCatalog, _request: &Request) -> Result<Res, Error> {
+    Ok(Res::IndexList(catalog.list_indexes()?))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AddDocuments {
     pub index: String,
-    // pub documents: Vec<Document>
-    pub documents: Vec<Vec<(String, Value)>>,
+    pub documents: Vec<DocumentInput>,
+    // Commit after adding the documents (default: decided by the commit policy)
+    pub commit: Option<bool>,
+    // What to do if some documents are invalid (default: reject all)
+    #[serde(default)]
+    pub on_error: OnError,
 }
 
-pub fn add_documents(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
+#[derive(Serialize, Debug)]
+pub struct AddDocumentsResponse {
+    pub added: usize,
+    pub errors: Vec<DocumentError>,
+}
+
+pub fn add_documents(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
     let req: AddDocuments = request.message()?;
     let handle = catalog.get_index(&req.index)?;
-    handle.add_documents(&req.documents)?;
+    let (added, errors) = handle.add_documents(&req.documents, req.commit, req.on_error)?;
+    Ok(Res::AddDocumentsResponse(AddDocumentsResponse {
+        added,
+        errors,
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Commit {
+    pub index: String,
+    pub payload: Option<String>,
+}
+
+pub fn commit(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let req: Commit = request.message()?;
+    let handle = catalog.get_index(&req.index)?;
+    handle.commit(req.payload)?;
+    Ok(Res::empty())
+}
+
+pub fn prepare_commit(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let name: String = request.message()?;
+    let handle = catalog.get_index(&name)?;
+    handle.prepare_commit()?;
+    Ok(Res::empty())
+}
+
+pub fn rollback(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let name: String = request.message()?;
+    let handle = catalog.get_index(&name)?;
+    handle.rollback()?;
     Ok(Res::empty())
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteDocuments {
+    pub index: String,
+    // The field to match the values against, e.g. "id"
+    pub field: String,
+    pub values: Vec<Value>,
+    // Commit after deleting the documents (default: decided by the commit policy)
+    pub commit: Option<bool>,
+}
+
+pub fn delete_documents(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let req: DeleteDocuments = request.message()?;
+    let handle = catalog.get_index(&req.index)?;
+    handle.delete_documents(&req.field, &req.values, req.commit)?;
+    Ok(Res::empty())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateDocuments {
+    pub index: String,
+    // The field to find the documents to replace by, e.g. "id"
+    pub field: String,
+    pub documents: Vec<DocumentInput>,
+    // Commit after updating the documents (default: decided by the commit policy)
+    pub commit: Option<bool>,
+    // What to do if some documents are invalid (default: reject all)
+    #[serde(default)]
+    pub on_error: OnError,
+}
+
+pub fn update_documents(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let req: UpdateDocuments = request.message()?;
+    let handle = catalog.get_index(&req.index)?;
+    let (added, errors) =
+        handle.update_documents(&req.field, &req.documents, req.commit, req.on_error)?;
+    Ok(Res::AddDocumentsResponse(AddDocumentsResponse {
+        added,
+        errors,
+    }))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Query {
     pub index: String,
     pub query: String,
-    pub limit: Option<u32>,
-    pub snippet_field: Option<String>,
+    #[serde(flatten)]
+    pub options: QueryOptions,
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QueryMulti {
     pub indexes: Vec<String>,
     pub query: String,
+    #[serde(flatten)]
+    pub options: QueryOptions,
+    // Cursors for the single indexes, by index name
+    #[serde(default)]
+    pub cursors: HashMap<String, String>,
+    // Rank the hits of all indexes together, as if they were one index
+    #[serde(default)]
+    pub merge: bool,
+    // Fail if some of the indexes don't exist (default: skip them)
+    #[serde(default)]
+    pub strict: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryResponse {
+    // Number of all documents that match the query, not only of the returned hits
+    pub total_hits: usize,
+    pub hits: Vec<QueryResponseDocument>,
+    // True if the query had a syntax error and was searched for its words instead
+    pub lenient_fallback: bool,
+    pub elapsed_ms: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryMultiResponse {
+    // Sum of the total hits of all indexes
+    pub total_hits: usize,
+    pub results: Vec<IndexQueryResponse>,
+    // Names of the requested indexes that don't exist
+    pub missing_indexes: Vec<String>,
+    pub elapsed_ms: f64,
 }
 
-// #[derive(Serialize, Debug)]
-type QueryResponse = Vec<QueryResponseDocument>;
-// pub struct QueryResponse {
-//     pub results: Vec<QueryResponseDocument>,
-// }
+#[derive(Serialize, Debug)]
+pub struct QueryMergedResponse {
+    pub total_hits: usize,
+    // The hits of all indexes, each with the name of its index
+    pub hits: Vec<QueryResponseDocument>,
+    pub missing_indexes: Vec<String>,
+    pub lenient_fallback: bool,
+    pub elapsed_ms: f64,
+}
 
-// #[derive(Serialize, Debug)]
-type QueryMultiResponse = Vec<(String, Vec<QueryResponseDocument>)>;
-// pub struct QueryMultiResponse {
-//     pub results: Vec<(String,Vec<QueryResponseDocument>)>,
-// }
+#[derive(Serialize, Debug)]
+pub struct IndexQueryResponse {
+    pub index: String,
+    pub total_hits: usize,
+    pub hits: Vec<QueryResponseDocument>,
+    pub lenient_fallback: bool,
+}
 
 #[derive(Serialize)]
 pub struct QueryResponseDocument {
     pub score: f32,
-    pub doc: NamedFieldDocument,
+    pub doc: JsonDocument,
     pub snippet: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub snippets: BTreeMap<String, SnippetOutput>,
+    pub address: HitAddress,
+    pub cursor: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
 }
 
 impl QueryResponseDocument {
-    pub fn from_tantivy_doc(
-        score: f32,
-        doc: NamedFieldDocument,
-        snippet: Option<String>,
-    ) -> Result<QueryResponseDocument, Error> {
+    pub fn from_hit(hit: QueryHit) -> Result<QueryResponseDocument, Error> {
         Ok(QueryResponseDocument {
-            score,
-            doc,
-            snippet,
+            score: hit.score,
+            doc: named_doc_to_json(hit.doc),
+            snippet: hit.snippet,
+            snippets: hit.snippets,
+            address: hit.address,
+            cursor: hit.cursor,
+            index: None,
         })
     }
 }
@@ -136,42 +305,91 @@ impl fmt::Debug for QueryResponseDocument {
     }
 }
 
-pub fn query(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
-    let req: Query = request.message()?;
-    // eprintln!("QUERY {:?}", req);
-    let handle = catalog.get_index(&req.index)?;
-    let tantivy_results = handle.query(&req.query, req.limit.unwrap_or(10), req.snippet_field)?;
-    let mut results = vec![];
-    for (score, doc, snippet) in tantivy_results {
-        let result = QueryResponseDocument::from_tantivy_doc(score.clone(), doc, snippet);
+fn response_documents(results: QueryResults) -> Vec<QueryResponseDocument> {
+    let mut documents = vec![];
+    for hit in results.hits {
+        let result = QueryResponseDocument::from_hit(hit);
         if let Ok(doc) = result {
-            results.push(doc)
+            documents.push(doc)
         }
     }
+    documents
+}
 
-    // let response = QueryResponse { results };
-    let response = results;
-    // Ok(response)
+/// Milliseconds since `start`, for the `elapsed_ms` of responses.
+pub fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+pub fn query(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let start = Instant::now();
+    let req: Query = request.message()?;
+    // eprintln!("QUERY {:?}", req);
+    let handle = catalog.get_index(&req.index)?;
+    let interrupt = Interrupt::new(request.cancel_token(), req.options.timeout_ms);
+    let results = handle.query(&req.query, &req.options, &interrupt)?;
+    let response = QueryResponse {
+        total_hits: results.total_hits,
+        lenient_fallback: results.lenient_fallback,
+        hits: response_documents(results),
+        elapsed_ms: elapsed_ms(start),
+    };
     Ok(Res::QueryResponse(response))
 }
-pub fn query_multi(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
+
+pub fn query_multi(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
+    let start = Instant::now();
     let req: QueryMulti = request.message()?;
-    let combined_results = catalog.query_multi(&req.query, &req.indexes)?;
-    let mut results: QueryMultiResponse = vec![];
-    for (index, index_results) in combined_results {
-        let mut inner_results = vec![];
-        for (score, doc, snippet) in index_results {
-            let result = QueryResponseDocument::from_tantivy_doc(score.clone(), doc, snippet);
-            if let Ok(doc) = result {
-                inner_results.push(doc);
+    let interrupt = Interrupt::new(request.cancel_token(), req.options.timeout_ms);
+    let missing_indexes = catalog.get_indexes(&req.indexes).1;
+    if req.strict && !missing_indexes.is_empty() {
+        let error = RpcError::new(ErrorCode::IndexNotFound, "Index not found.")
+            .with_details(serde_json::json!({ "indexes": missing_indexes }));
+        return Err(error.into());
+    }
+    if req.merge {
+        let results = catalog.query_merged(&req.query, &req.indexes, &req.options, &interrupt)?;
+        let mut hits = vec![];
+        for (index, hit) in results.hits {
+            if let Ok(mut doc) = QueryResponseDocument::from_hit(hit) {
+                doc.index = Some(index);
+                hits.push(doc);
             }
         }
-        results.push((index, inner_results));
+        let response = QueryMergedResponse {
+            total_hits: results.total_hits,
+            hits,
+            missing_indexes,
+            lenient_fallback: results.lenient_fallback,
+            elapsed_ms: elapsed_ms(start),
+        };
+        return Ok(Res::QueryMergedResponse(response));
+    }
+    let combined_results = catalog.query_multi(
+        &req.query,
+        &req.indexes,
+        &req.options,
+        &req.cursors,
+        &interrupt,
+    )?;
+    let mut total_hits = 0;
+    let mut results = vec![];
+    for (index, index_results) in combined_results {
+        total_hits += index_results.total_hits;
+        results.push(IndexQueryResponse {
+            index,
+            total_hits: index_results.total_hits,
+            lenient_fallback: index_results.lenient_fallback,
+            hits: response_documents(index_results),
+        });
     }
 
-    // let response = QueryMultiResponse { results };
-    let response = results;
-    // Ok(response)
+    let response = QueryMultiResponse {
+        total_hits,
+        results,
+        missing_indexes,
+        elapsed_ms: elapsed_ms(start),
+    };
     Ok(Res::QueryMultiResponse(response))
 }
 
@@ -188,14 +406,14 @@ pub struct AddSegments {
     pub segments: Vec<SegmentInfo>,
 }
 
-pub fn add_segment(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
+pub fn add_segment(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
     let req: AddSegment = request.message()?;
     let handle = catalog.get_index(&req.index)?;
     handle.add_segment(&req.segment_id, req.max_doc)?;
     Ok(Res::empty())
 }
 
-pub fn add_segments(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, Error> {
+pub fn add_segments(catalog: &IndexCatalog, request: &Request) -> Result<Res, Error> {
     let req: AddSegments = request.message()?;
     let handle = catalog.get_index(&req.index)?;
     handle.add_segments(req.segments)?;
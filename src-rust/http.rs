@@ -0,0 +1,290 @@
+use crate::collector::Interrupt;
+use crate::document::{get_key_field, term_for_value, DocumentInput, OnError};
+use crate::error::{ErrorCode, RpcError};
+use crate::handles::parse_schema;
+use crate::index::IndexCatalog;
+use crate::search::search_index;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use tantivy::collector::Count;
+use tantivy::query::TermQuery;
+use tantivy::schema::{IndexRecordOption, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use toshi_types::Search;
+
+/// HTTP server
+///
+/// Serves the index catalog over HTTP, with the endpoints of the Toshi search server:
+///
+/// - `PUT /{index}`: Create an index. The body is the schema.
+/// - `PUT /{index}/_add`: Add documents.
+/// - `POST /{index}`: Search with a Toshi `Search` body.
+/// - `GET /{index}/_summary`: Get the metadata of an index.
+/// - `DELETE /{index}`: Delete the documents that match a set of terms.
+///
+/// Errors are sent with the same JSON body as in the RPC protocol.
+pub fn serve(catalog: IndexCatalog, address: &str, num_workers: usize) -> io::Result<()> {
+    // The errors of binding the address are I/O errors.
+    let server = Server::http(address).map_err(|err| match err.downcast::<io::Error>() {
+        Ok(err) => *err,
+        Err(err) => io::Error::new(io::ErrorKind::InvalidInput, err),
+    })?;
+    eprintln!("Listening for HTTP on {}", address);
+    let server = Arc::new(server);
+    let catalog = Arc::new(catalog);
+    let workers: Vec<_> = (0..num_workers)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let catalog = Arc::clone(&catalog);
+            thread::spawn(move || work(&server, &catalog))
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+fn work(server: &Server, catalog: &IndexCatalog) {
+    loop {
+        let mut request = match server.recv() {
+            Ok(request) => request,
+            // The server only fails to receive requests once it is shut down.
+            Err(err) => {
+                eprintln!("Could not receive HTTP request, stopping worker: {}", err);
+                return;
+            }
+        };
+        let (status, body) = match onrequest(catalog, &mut request) {
+            Ok(body) => (200, body),
+            Err(err) => {
+                let err = RpcError::from(err);
+                (
+                    status_code(err.code),
+                    serde_json::to_value(err).unwrap_or_default(),
+                )
+            }
+        };
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(json_header());
+        if let Err(err) = request.respond(response) {
+            eprintln!("Could not send HTTP response: {}", err);
+        }
+    }
+}
+
+fn onrequest(catalog: &IndexCatalog, request: &mut Request) -> anyhow::Result<JsonValue> {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let method = request.method().clone();
+    match (method, segments.as_slice()) {
+        (Method::Put, [index]) => create_index(catalog, index, read_body(request)?),
+        (Method::Put, [index, "_add"]) => add_documents(catalog, index, read_body(request)?),
+        (Method::Post, [index]) => search(catalog, index, read_body(request)?),
+        (Method::Get, [index, "_summary"]) => summary(catalog, index),
+        (Method::Delete, [index]) => delete_documents(catalog, index, read_body(request)?),
+        (method, _) => Err(RpcError::new(ErrorCode::MethodNotFound, "Route not found.")
+            .with_details(serde_json::json!({ "method": method.to_string(), "path": path }))
+            .into()),
+    }
+}
+
+fn create_index(catalog: &IndexCatalog, index: &str, body: JsonValue) -> anyhow::Result<JsonValue> {
+    let schema = parse_schema(body)?;
+    catalog.create_index(index.to_string(), schema, Default::default())?;
+    Ok(JsonValue::Null)
+}
+
+// Without options, the commit policy of the index decides when to commit.
+#[derive(Deserialize)]
+struct CommitOptions {
+    #[serde(default)]
+    commit: bool,
+}
+
+#[derive(Deserialize)]
+struct AddDocument {
+    #[serde(default)]
+    options: Option<CommitOptions>,
+    // A single document or an array of documents
+    document: JsonValue,
+}
+
+#[derive(Serialize)]
+struct DocsAffected {
+    docs_affected: u64,
+}
+
+fn add_documents(
+    catalog: &IndexCatalog,
+    index: &str,
+    body: JsonValue,
+) -> anyhow::Result<JsonValue> {
+    let req: AddDocument = serde_json::from_value(body)?;
+    let handle = catalog.get_index(index)?;
+    let documents: Vec<Map<String, JsonValue>> = match req.document {
+        JsonValue::Array(documents) => serde_json::from_value(JsonValue::Array(documents))?,
+        document => vec![serde_json::from_value(document)?],
+    };
+    let documents: Vec<DocumentInput> = documents.into_iter().map(DocumentInput::Object).collect();
+    let commit = req.options.map(|options| options.commit);
+    let (added, errors) = handle.add_documents(&documents, commit, OnError::Reject)?;
+    if !errors.is_empty() {
+        let err = RpcError::new(ErrorCode::InvalidArgument, "Invalid documents.")
+            .with_details(serde_json::to_value(errors)?);
+        return Err(err.into());
+    }
+    Ok(serde_json::to_value(DocsAffected {
+        docs_affected: added as u64,
+    })?)
+}
+
+fn search(catalog: &IndexCatalog, index: &str, body: JsonValue) -> anyhow::Result<JsonValue> {
+    let search: Search = serde_json::from_value(body)?;
+    let handle = catalog.get_index(index)?;
+    let reader = handle.get_reader()?;
+    let results = search_index(
+        &handle.index,
+        &reader,
+        search,
+        &Default::default(),
+        &Default::default(),
+        &Interrupt::none(),
+    )?;
+    Ok(serde_json::to_value(results)?)
+}
+
+fn summary(catalog: &IndexCatalog, index: &str) -> anyhow::Result<JsonValue> {
+    let handle = catalog.get_index(index)?;
+    let metas = handle.index.load_metas()?;
+    Ok(serde_json::json!({ "summaries": metas }))
+}
+
+#[derive(Deserialize)]
+struct DeleteDoc {
+    #[serde(default)]
+    options: Option<CommitOptions>,
+    terms: HashMap<String, String>,
+}
+
+fn delete_documents(
+    catalog: &IndexCatalog,
+    index: &str,
+    body: JsonValue,
+) -> anyhow::Result<JsonValue> {
+    let req: DeleteDoc = serde_json::from_value(body)?;
+    let handle = catalog.get_index(index)?;
+    let commit = req.options.map(|options| options.commit);
+    let schema = handle.index.schema();
+    let searcher = handle.get_reader()?.searcher();
+    let mut docs_affected = 0;
+    for (field_name, value) in &req.terms {
+        let value = Value::Str(value.clone());
+        // Count the matches before deleting, tantivy does not report them.
+        let field = get_key_field(&schema, field_name)?;
+        let term = term_for_value(&schema, field, &value)?;
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+        docs_affected += searcher.search(&query, &Count)? as u64;
+        handle.delete_documents(field_name, &[value], commit)?;
+    }
+    Ok(serde_json::to_value(DocsAffected { docs_affected })?)
+}
+
+fn read_body(request: &mut Request) -> anyhow::Result<JsonValue> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+    if body.trim().is_empty() {
+        return Ok(JsonValue::Null);
+    }
+    Ok(serde_json::from_str(&body)?)
+}
+
+fn status_code(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::IndexNotFound | ErrorCode::MethodNotFound => 404,
+        ErrorCode::QueryParseError
+        | ErrorCode::SchemaInvalid
+        | ErrorCode::InvalidRequest
+        | ErrorCode::InvalidArgument => 400,
+        ErrorCode::Cancelled | ErrorCode::Timeout => 503,
+        ErrorCode::Io | ErrorCode::Internal => 500,
+    }
+}
+
+fn json_header() -> Header {
+    Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("Static header is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use tantivy::schema::{Schema, STORED, TEXT};
+
+    /// Send a request and return the status code and the JSON body of the response.
+    fn send(address: &str, method: &str, path: &str, body: &str) -> (u16, JsonValue) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn search_errors() {
+        let tmp_dir = tempdir::TempDir::new("test").unwrap();
+        let catalog = IndexCatalog::new(tmp_dir.path().to_path_buf()).unwrap();
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("body", TEXT | STORED);
+        catalog
+            .create_ram_index(
+                "books".to_string(),
+                schema_builder.build(),
+                Default::default(),
+            )
+            .unwrap();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap().to_string();
+        thread::spawn(move || work(&server, &catalog));
+
+        let (status, body) = send(
+            &address,
+            "POST",
+            "/books",
+            r#"{ "query": { "raw": "body:(x" } }"#,
+        );
+        assert_eq!(status, 400);
+        assert_eq!(body["code"], "query_parse_error");
+        assert_eq!(body["details"]["kind"], "syntax_error");
+
+        let (status, body) = send(
+            &address,
+            "POST",
+            "/missing",
+            r#"{ "query": { "raw": "x" } }"#,
+        );
+        assert_eq!(status, 404);
+        assert_eq!(body["code"], "index_not_found");
+
+        let (status, body) = send(&address, "POST", "/books", r#"{ "query": { "raw": "x" } }"#);
+        assert_eq!(status, 200);
+        assert_eq!(body["hits"], 0);
+    }
+}
@@ -1,23 +1,55 @@
-use once_cell::sync::Lazy;
+use crate::bm25::{self, TermStatistics};
+use crate::collector::{Cursor, HitAddress, Interrupt, SearchAfter};
+use crate::commit::{commit_writer, ensure_auto_commit, CommitState};
+use crate::document::{
+    build_documents, get_key_field, term_for_value, to_named_doc, DocumentError, DocumentErrorKind,
+    DocumentInput, OnError,
+};
+use crate::error::{ErrorCode, RpcError};
+use crate::filter::apply_filters;
+use crate::fuzzy::{self, QueryMode};
+use crate::options::{IndexOptions, QueryOptions, QueryParserOptions};
+use crate::snippet::{SnippetOutput, Snippets};
+use crate::sort::Sort;
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
+use tantivy::collector::{Count, MultiCollector, TopDocs};
+use tantivy::query::{Query, QueryParser};
 use tantivy::schema::*;
 use tantivy::{
     self, Directory, Index, IndexMeta, IndexReader, IndexWriter, ReloadPolicy, Result, SegmentId,
-    SnippetGenerator, TantivyError,
+    TantivyError,
 };
 
 pub struct IndexCatalog {
     pub base_path: PathBuf,
-    pub indexes: HashMap<String, IndexHandle>,
+    pub indexes: RwLock<HashMap<String, Arc<IndexHandle>>>,
+}
+
+/// Where the files of an index are kept.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Storage {
+    Disk,
+    Ram,
+}
+
+/// The schema of an index, as returned by `get_schema` and `list_indexes`.
+#[derive(Serialize, Debug)]
+pub struct IndexInfo {
+    pub name: String,
+    pub storage: Storage,
+    /// The fields with their options, in the JSON format of tantivy schemas.
+    pub schema: Schema,
+    /// The tokenizer of each indexed text and JSON field, by field name.
+    pub tokenizers: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,7 +66,7 @@ impl IndexCatalog {
 
         let mut catalog = IndexCatalog {
             base_path,
-            indexes: HashMap::new(),
+            indexes: RwLock::new(HashMap::new()),
         };
 
         catalog.load_all();
@@ -43,17 +75,15 @@ impl IndexCatalog {
     }
 
     fn mkdir(base_path: &PathBuf) -> io::Result<()> {
-        fs::create_dir_all(&base_path)
+        fs::create_dir_all(base_path)
     }
 
     fn load_all(&mut self) {
         //let mut index_paths = vec![];
         if let Ok(entries) = fs::read_dir(&self.base_path) {
             // eprintln!("load all {:?}", entries);
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    self.load_from_dir_entry(entry);
-                }
+            for entry in entries.flatten() {
+                self.load_from_dir_entry(entry);
             }
         }
     }
@@ -63,14 +93,23 @@ impl IndexCatalog {
         path_to_metajson.push("meta.json");
         if path_to_metajson.exists() {
             let name = entry.file_name().into_string();
-            if let Ok(name) = name {
+            // Directories with names that are not valid index names are not loaded.
+            if let Some(name) = name.ok().filter(|name| validate_index_name(name).is_ok()) {
                 let result = Index::open_in_dir(entry.path());
                 match result {
-                    Ok(index) => {
-                        let handle = IndexHandle::new(index);
-                        // eprintln!("Loaded index: {}", &name);
-                        self.indexes.insert(name, handle);
-                    }
+                    Ok(index) => match IndexHandle::open(index, Storage::Disk) {
+                        Ok(handle) => {
+                            // eprintln!("Loaded index: {}", &name);
+                            if let Ok(indexes) = self.indexes.get_mut() {
+                                indexes.insert(name, Arc::new(handle));
+                            }
+                        }
+                        Err(err) => eprintln!(
+                            "Loading options of index {:?} failed with error: {:#?}",
+                            entry.path(),
+                            err
+                        ),
+                    },
                     Err(err) => eprintln!(
                         "Opening index {:?} failed with error: {:#?}",
                         entry.path(),
@@ -86,211 +125,694 @@ impl IndexCatalog {
         }
     }
 
-    fn get_indexpath(&mut self, name: &str) -> PathBuf {
+    fn get_indexpath(&self, name: &str) -> Result<PathBuf> {
+        validate_index_name(name)?;
         let mut index_path = self.base_path.clone();
-        index_path.push(&name);
-        index_path
+        index_path.push(name);
+        Ok(index_path)
     }
 
-    pub fn delete_index(&mut self, name: String) -> Result<()> {
-        let index_path = self.get_indexpath(&name);
+    pub fn delete_index(&self, name: String) -> Result<()> {
+        let index_path = self.get_indexpath(&name)?;
+        // Drop the handle first, so that its writer releases the lock of the directory.
+        self.indexes.write()?.remove(&name);
         fs::remove_dir_all(&index_path)?;
-        self.indexes.remove(&name);
         Ok(())
     }
 
-    pub fn create_index(&mut self, name: String, schema: Schema) -> Result<()> {
+    pub fn create_index(&self, name: String, schema: Schema, options: IndexOptions) -> Result<()> {
         // eprintln!("create_index {}", name);
-        let index_path = self.get_indexpath(&name);
+        let index_path = self.get_indexpath(&name)?;
         fs::create_dir_all(&index_path)?;
         let index = Index::create_in_dir(&index_path, schema)?;
-        let handle = IndexHandle::new(index);
-        self.indexes.insert(name, handle);
+        let handle = IndexHandle::new(index, Storage::Disk);
+        if let Err(err) = handle.set_options(options) {
+            fs::remove_dir_all(&index_path)?;
+            return Err(err);
+        }
+        self.indexes.write()?.insert(name, Arc::new(handle));
         Ok(())
     }
 
-    pub fn create_ram_index(&mut self, name: String, schema: Schema) -> Result<()> {
+    pub fn create_ram_index(
+        &self,
+        name: String,
+        schema: Schema,
+        options: IndexOptions,
+    ) -> Result<()> {
+        validate_index_name(&name)?;
         let index = Index::create_in_ram(schema);
-        let handle = IndexHandle::new(index);
-        self.indexes.insert(name, handle);
+        let handle = IndexHandle::new(index, Storage::Ram);
+        handle.set_options(options)?;
+        self.indexes.write()?.insert(name, Arc::new(handle));
         Ok(())
     }
 
-    pub fn get_index(&mut self, name: &String) -> Result<&mut IndexHandle> {
+    pub fn get_index(&self, name: &str) -> anyhow::Result<Arc<IndexHandle>> {
         // eprintln!("get_index {}", name);
         // eprintln!("indexes: {:?}", self.indexes.keys());
         // eprintln!("get_index", name);
-        let handle: &mut IndexHandle = match self.indexes.get_mut(name) {
-            Some(handle) => Ok(handle),
-            None => Err(TantivyError::InvalidArgument(
-                "Index not found.".to_string(),
-            )),
+        validate_index_name(name)?;
+        let indexes = self.indexes.read().map_err(TantivyError::from)?;
+        let handle = match indexes.get(name) {
+            Some(handle) => Ok(Arc::clone(handle)),
+            None => Err(RpcError::index_not_found(name)),
         }?;
         Ok(handle)
     }
+
+    /// The schema of an index.
+    pub fn index_info(&self, name: &str) -> anyhow::Result<IndexInfo> {
+        Ok(self.get_index(name)?.info(name))
+    }
+
+    /// The schemas of all indexes, sorted by name.
+    pub fn list_indexes(&self) -> Result<Vec<IndexInfo>> {
+        let indexes = self.indexes.read()?;
+        let mut infos: Vec<IndexInfo> = indexes
+            .iter()
+            .map(|(name, handle)| handle.info(name))
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(infos)
+    }
+
+    /// Get several indexes. Returns the found indexes and the names of the missing ones.
+    pub fn get_indexes(&self, names: &[String]) -> (Vec<(String, Arc<IndexHandle>)>, Vec<String>) {
+        let mut handles = vec![];
+        let mut missing = vec![];
+        for name in names {
+            match self.get_index(name) {
+                Ok(handle) => handles.push((name.clone(), handle)),
+                Err(_) => missing.push(name.clone()),
+            }
+        }
+        (handles, missing)
+    }
+
+    /// Query several indexes. `cursors` are the cursors for the single indexes.
+    /// The limit defaults to 100 hits per index. Unknown indexes are skipped.
     pub fn query_multi(
-        &mut self,
-        query: &String,
-        indexes: &Vec<String>,
-    ) -> Result<Vec<(String, Vec<(f32, NamedFieldDocument, Option<String>)>)>> {
+        &self,
+        query: &str,
+        indexes: &[String],
+        options: &QueryOptions,
+        cursors: &HashMap<String, String>,
+        interrupt: &Interrupt,
+    ) -> anyhow::Result<Vec<(String, QueryResults)>> {
         let mut results = vec![];
-        for entry in indexes {
-            let index_key = entry;
-            if self.indexes.contains_key(index_key) {
-                let index = self.get_index(&index_key.to_string())?;
-                let res = index.query(query, 100, None)?;
-                results.push((index_key.clone(), res));
-            }
+        for (index_key, index) in self.get_indexes(indexes).0 {
+            let options = QueryOptions {
+                limit: Some(options.limit.unwrap_or(100)),
+                cursor: cursors.get(&index_key).cloned(),
+                ..options.clone()
+            };
+            let res = index.query(query, &options, interrupt)?;
+            results.push((index_key, res));
         }
         Ok(results)
     }
+
+    /// Query several indexes as one corpus, with a single ranking of the hits of all indexes.
+    /// The limit defaults to 100 hits. Unknown indexes are skipped.
+    ///
+    /// tantivy scores hits with the BM25 statistics of their own index. To make scores of
+    /// different indexes comparable, hits are scored with BM25 with the document frequencies
+    /// and field lengths of all indexes, see `bm25::with_statistics`.
+    pub fn query_merged(
+        &self,
+        query: &str,
+        indexes: &[String],
+        options: &QueryOptions,
+        interrupt: &Interrupt,
+    ) -> anyhow::Result<MergedQueryResults> {
+        if options.cursor.is_some() {
+            let message = "Cursors are not supported when merging indexes, use offset.";
+            return Err(RpcError::new(ErrorCode::InvalidArgument, message).into());
+        }
+        if !options.sort.is_empty() {
+            let message = "Sorting is not supported when merging indexes.";
+            return Err(RpcError::new(ErrorCode::InvalidArgument, message).into());
+        }
+        let handles = self.get_indexes(indexes).0;
+        let limit = options.limit.unwrap_or(100) as usize;
+        let offset = options.offset.unwrap_or(0) as usize;
+
+        let mut statistics = vec![];
+        for (_name, handle) in &handles {
+            statistics.push(handle.term_statistics(query, options)?);
+        }
+        let global = TermStatistics::sum(&statistics);
+
+        let index_options = QueryOptions {
+            limit: Some((offset + limit) as u32),
+            offset: None,
+            ..options.clone()
+        };
+        let mut total_hits = 0;
+        let mut hits = vec![];
+        let mut lenient_fallback = false;
+        for (name, handle) in &handles {
+            let results =
+                handle.query_with_statistics(query, &index_options, Some(&global), interrupt)?;
+            lenient_fallback |= results.lenient_fallback;
+            total_hits += results.total_hits;
+            for hit in results.hits {
+                hits.push((name.clone(), hit));
+            }
+        }
+        // Stable sort, so hits with equal scores keep the order of the indexes.
+        hits.sort_by(|(_, a), (_, b)| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let hits = hits.into_iter().skip(offset).take(limit).collect();
+        Ok(MergedQueryResults {
+            total_hits,
+            hits,
+            lenient_fallback,
+        })
+    }
 }
 
+/// The results of a query over several indexes, with the index name of each hit.
+#[derive(Debug)]
+pub struct MergedQueryResults {
+    pub total_hits: usize,
+    pub hits: Vec<(String, QueryHit)>,
+    pub lenient_fallback: bool,
+}
+
+/// The results of a query.
+#[derive(Debug)]
+pub struct QueryResults {
+    /// Number of all documents that match the query.
+    pub total_hits: usize,
+    pub hits: Vec<QueryHit>,
+    /// True if the query had a syntax error and was searched for its words instead.
+    pub lenient_fallback: bool,
+}
+
+/// A hit of a query.
+#[derive(Debug)]
+pub struct QueryHit {
+    pub score: f32,
+    pub doc: NamedFieldDocument,
+    pub snippet: Option<String>,
+    pub snippets: BTreeMap<String, SnippetOutput>,
+    pub address: HitAddress,
+    /// Pass as cursor to get the hits after this one.
+    pub cursor: String,
+}
+
+/// Index handle
+///
+/// Holds an index together with its lazily created reader and writer.
+/// All methods take `&self`, so that a handle can be shared between threads.
 pub struct IndexHandle {
     pub index: Index,
-    pub reader: Option<Arc<IndexReader>>,
-    // pub writer: Option<IndexWriter>,
-    pub writer: Option<Arc<RwLock<IndexWriter>>>,
-    pub query_parser: Option<QueryParser>,
+    pub storage: Storage,
+    reader: OnceCell<Arc<IndexReader>>,
+    writer: OnceCell<Arc<RwLock<IndexWriter>>>,
+    options: RwLock<IndexOptions>,
+    commit_state: Arc<Mutex<CommitState>>,
 }
 
 impl IndexHandle {
-    pub fn new(index: Index) -> Self {
+    pub fn new(index: Index, storage: Storage) -> Self {
         IndexHandle {
             index,
-            reader: None,
-            writer: None,
-            query_parser: None,
+            storage,
+            reader: OnceCell::new(),
+            writer: OnceCell::new(),
+            options: RwLock::new(IndexOptions::default()),
+            commit_state: Arc::new(Mutex::new(CommitState::new(Default::default()))),
         }
     }
 
-    pub fn add_documents(&mut self, docs: &[Vec<(String, Value)>]) -> Result<()> {
+    /// Open an existing index together with its saved options.
+    pub fn open(index: Index, storage: Storage) -> Result<Self> {
+        let options = IndexOptions::load(index.directory())?;
+        let handle = IndexHandle::new(index, storage);
+        handle.commit_state.lock()?.policy = options.commit_policy.clone();
+        *handle.options.write()? = options;
+        Ok(handle)
+    }
+
+    /// The schema of this index, with the tokenizers of its fields.
+    pub fn info(&self, name: &str) -> IndexInfo {
         let schema = self.index.schema();
+        let mut tokenizers = BTreeMap::new();
+        for (_, entry) in schema.fields() {
+            let indexing = match entry.field_type() {
+                FieldType::Str(options) => options.get_indexing_options(),
+                FieldType::JsonObject(options) => options.get_text_indexing_options(),
+                _ => None,
+            };
+            if let Some(indexing) = indexing {
+                tokenizers.insert(entry.name().to_string(), indexing.tokenizer().to_string());
+            }
+        }
+        IndexInfo {
+            name: name.to_string(),
+            storage: self.storage,
+            schema,
+            tokenizers,
+        }
+    }
+
+    pub fn options(&self) -> Result<IndexOptions> {
+        Ok(self.options.read()?.clone())
+    }
+
+    /// Change and save the options of this index.
+    pub fn set_options(&self, options: IndexOptions) -> Result<()> {
+        self.build_query_parser(&options.query_parser)?;
+        for field_name in &options.required_fields {
+            if self.index.schema().get_field(field_name).is_none() {
+                return Err(TantivyError::InvalidArgument(format!(
+                    "Field not found: {}",
+                    field_name
+                )));
+            }
+        }
+        options.save(self.index.directory())?;
+        self.commit_state.lock()?.policy = options.commit_policy.clone();
+        *self.options.write()? = options;
+        if let Some(writer) = self.writer.get() {
+            ensure_auto_commit(writer, &self.commit_state);
+        }
+        Ok(())
+    }
+
+    /// Add documents to the index.
+    ///
+    /// Documents are checked against the schema. If some are invalid, either none
+    /// or only the valid documents are added, depending on `on_error`.
+    /// Returns the number of added documents and the errors of the invalid documents.
+    ///
+    /// If `commit` is not set, the index' commit policy decides when the documents are committed.
+    pub fn add_documents(
+        &self,
+        docs: &[DocumentInput],
+        commit: Option<bool>,
+        on_error: OnError,
+    ) -> Result<(usize, Vec<DocumentError>)> {
+        let schema = self.index.schema();
+        let options = self.options()?;
+        let (documents, errors) = build_documents(&schema, docs, &options.required_fields);
+        if !errors.is_empty() && on_error == OnError::Reject {
+            return Ok((0, errors));
+        }
+
+        let added = documents.len();
         let writer_lock = self.get_writer()?;
         {
             let writer = writer_lock.read()?;
 
-            for doc in docs {
-                let mut document = Document::default();
-                for (field_name, value) in doc {
-                    match schema.get_field(&field_name) {
-                        Some(field) => document.add_field_value(field, value.clone()),
-                        None => eprintln!("Invalid field: {}", field_name),
-                    }
-                }
-
+            for (_position, document) in documents {
                 let _opstamp = writer.add_document(document);
                 // eprintln!("added {:?}", _opstamp);
             }
         }
-        {
-            // eprintln!("now commit");
-            let mut writer = writer_lock.write()?;
-            let _opstamp = writer.commit()?;
-            // eprintln!("committed {:?}", _opstamp);
+        self.after_changes(added as u64, commit)?;
+        Ok((added, errors))
+    }
+
+    /// Commit all pending changes and make them visible to readers.
+    ///
+    /// The optional payload is stored in the index meta together with the commit.
+    pub fn commit(&self, payload: Option<String>) -> Result<()> {
+        let writer_lock = self.get_writer()?;
+        commit_writer(&writer_lock, &self.commit_state, payload)?;
+        self.reload_reader()
+    }
+
+    /// Record uncommitted changes and commit if requested or if the commit policy says so.
+    fn after_changes(&self, num_changes: u64, commit: Option<bool>) -> Result<()> {
+        let policy_commit = self.commit_state.lock()?.add_pending(num_changes, commit);
+        if commit.unwrap_or(policy_commit) {
+            self.commit(None)?;
         }
         Ok(())
     }
 
-    pub fn get_writer(&mut self) -> Result<Arc<RwLock<IndexWriter>>> {
-        self.ensure_writer()?;
-        Ok(Arc::clone(self.writer.as_ref().unwrap()))
+    /// Persist all pending changes without making them visible to readers.
+    ///
+    /// The changes become visible with the next call to `commit`, or are
+    /// discarded by `rollback`.
+    pub fn prepare_commit(&self) -> Result<()> {
+        let writer_lock = self.get_writer()?;
+        let mut writer = writer_lock.write()?;
+        let _prepared_commit = writer.prepare_commit()?;
+        self.commit_state.lock()?.open_transaction();
+        Ok(())
     }
 
-    pub fn get_reader(&mut self) -> Result<Arc<IndexReader>> {
-        self.ensure_reader()?;
-        Ok(Arc::clone(self.reader.as_ref().unwrap()))
+    /// Discard all changes since the last commit.
+    pub fn rollback(&self) -> Result<()> {
+        let writer_lock = self.get_writer()?;
+        let mut writer = writer_lock.write()?;
+        let _opstamp = writer.rollback()?;
+        self.commit_state.lock()?.reset();
+        Ok(())
+    }
+
+    /// Delete all documents that contain one of `values` in the field `field_name`.
+    pub fn delete_documents(
+        &self,
+        field_name: &str,
+        values: &[Value],
+        commit: Option<bool>,
+    ) -> Result<()> {
+        let schema = self.index.schema();
+        let field = get_key_field(&schema, field_name)?;
+        let terms = values
+            .iter()
+            .map(|value| term_for_value(&schema, field, value))
+            .collect::<Result<Vec<Term>>>()?;
+
+        let writer_lock = self.get_writer()?;
+        {
+            let writer = writer_lock.read()?;
+            for term in terms {
+                let _opstamp = writer.delete_term(term);
+            }
+        }
+        self.after_changes(values.len() as u64, commit)
+    }
+
+    /// Replace documents by term: For each document, all documents that have the same
+    /// value in the field `field_name` are deleted before the document is added.
+    /// Deletes and adds become visible together with the next commit.
+    ///
+    /// Invalid documents are handled as in `add_documents`.
+    pub fn update_documents(
+        &self,
+        field_name: &str,
+        docs: &[DocumentInput],
+        commit: Option<bool>,
+        on_error: OnError,
+    ) -> Result<(usize, Vec<DocumentError>)> {
+        let schema = self.index.schema();
+        let field = get_key_field(&schema, field_name)?;
+        let options = self.options()?;
+        let (documents, mut errors) = build_documents(&schema, docs, &options.required_fields);
+        let mut updates = vec![];
+        for (position, document) in documents {
+            match document.get_first(field) {
+                Some(value) => updates.push((term_for_value(&schema, field, value)?, document)),
+                None => errors.push(DocumentError {
+                    document: position,
+                    field: field_name.to_string(),
+                    kind: DocumentErrorKind::MissingField,
+                    message: format!("Missing field to update by: {}", field_name),
+                }),
+            }
+        }
+        if !errors.is_empty() && on_error == OnError::Reject {
+            return Ok((0, errors));
+        }
+
+        let updated = updates.len();
+        let writer_lock = self.get_writer()?;
+        {
+            let writer = writer_lock.read()?;
+            for (term, document) in updates {
+                let _opstamp = writer.delete_term(term);
+                let _opstamp = writer.add_document(document);
+            }
+        }
+        self.after_changes(updated as u64, commit)?;
+        Ok((updated, errors))
     }
 
-    fn ensure_writer(&mut self) -> Result<()> {
-        if self.writer.is_none() {
+    pub fn get_writer(&self) -> Result<Arc<RwLock<IndexWriter>>> {
+        let writer = self.writer.get_or_try_init(|| -> Result<_> {
             let writer = self.index.writer(50_000_000)?;
             let writer = Arc::new(RwLock::new(writer));
-            self.writer = Some(writer);
-        }
-        Ok(())
+            ensure_auto_commit(&writer, &self.commit_state);
+            Ok(writer)
+        })?;
+        Ok(Arc::clone(writer))
     }
 
-    fn ensure_reader(&mut self) -> Result<()> {
-        if self.reader.is_none() {
+    pub fn get_reader(&self) -> Result<Arc<IndexReader>> {
+        let reader = self.reader.get_or_try_init(|| -> Result<_> {
             let reader = self
                 .index
                 .reader_builder()
                 .reload_policy(ReloadPolicy::OnCommit)
                 .try_into()?;
-            let reader = Arc::new(reader);
-            self.reader = Some(reader);
+            Ok(Arc::new(reader))
+        })?;
+        Ok(Arc::clone(reader))
+    }
+
+    /// Make the last commit visible to searches right away.
+    ///
+    /// The reader reloads on commits by itself, but only after a short delay.
+    fn reload_reader(&self) -> Result<()> {
+        if let Some(reader) = self.reader.get() {
+            reader.reload()?;
         }
         Ok(())
     }
 
-    fn ensure_query_parser(&mut self) -> Result<()> {
+    fn build_query_parser(&self, options: &QueryParserOptions) -> Result<QueryParser> {
+        let fields = self.default_fields(options)?;
+        let mut query_parser = QueryParser::for_index(
+            &self.index,
+            fields.iter().map(|(field, _)| *field).collect(),
+        );
+        for (field_name, boost) in &options.boosts {
+            query_parser.set_field_boost(self.get_field(field_name)?, *boost);
+        }
+        if options.conjunction {
+            query_parser.set_conjunction_by_default();
+        }
+        Ok(query_parser)
+    }
+
+    /// The fields to search for terms without a field name, with their boosts.
+    fn default_fields(&self, options: &QueryParserOptions) -> Result<Vec<(Field, f32)>> {
         let schema = self.index.schema();
-        if self.query_parser.is_none() {
-            let mut fields = vec![];
-            let all_fields = schema.fields();
-            for (_field, field_entry) in all_fields {
-                if !field_entry.is_indexed() {
-                    break;
+        let mut fields = vec![];
+        match &options.default_fields {
+            Some(field_names) => {
+                for field_name in field_names {
+                    fields.push(self.get_field(field_name)?);
                 }
-                if let Some(field) = schema.get_field(field_entry.name()) {
-                    fields.push(field);
-                } // else cannot happen.
             }
-            let query_parser = QueryParser::for_index(&self.index, fields);
-            self.query_parser = Some(query_parser);
+            None => fields = text_fields(&schema),
+        }
+        for field_name in options.boosts.keys() {
+            self.get_field(field_name)?;
+        }
+        let boost = |field: Field| {
+            let boost = options.boosts.get(schema.get_field_name(field));
+            boost.copied().unwrap_or(1.0)
+        };
+        Ok(fields
+            .into_iter()
+            .map(|field| (field, boost(field)))
+            .collect())
+    }
+
+    fn get_field(&self, field_name: &str) -> Result<Field> {
+        self.index.schema().get_field(field_name).ok_or_else(|| {
+            TantivyError::InvalidArgument(format!("Field not found: {}", field_name))
+        })
+    }
+
+    /// The query parser options of the index, with the overrides of a query.
+    fn query_parser_options(&self, options: &QueryOptions) -> Result<QueryParserOptions> {
+        Ok(self
+            .options
+            .read()
+            .map_err(TantivyError::from)?
+            .query_parser
+            .with_overrides(options))
+    }
+
+    /// Parse a string query. Returns the query and whether the lenient fallback was used.
+    ///
+    /// With `options.lenient`, a query that can't be parsed is reduced to its words,
+    /// without any query syntax, so that typos in search boxes don't fail the search.
+    ///
+    /// In the `prefix` and `fuzzy` modes, the query is not parsed but searched for its words.
+    fn parse_query(
+        &self,
+        query: &str,
+        options: &QueryOptions,
+        parser_options: &QueryParserOptions,
+    ) -> anyhow::Result<(Box<dyn Query>, bool)> {
+        if options.mode != QueryMode::Syntax {
+            let fields = self.default_fields(parser_options)?;
+            let query = fuzzy::build_query(
+                &self.index,
+                &fields,
+                query,
+                options.mode,
+                options.edit_distance,
+                parser_options.conjunction,
+            )?;
+            return Ok((query, false));
+        }
+        let query_parser = self.build_query_parser(parser_options)?;
+        match query_parser.parse_query(query) {
+            Ok(parsed_query) => Ok((parsed_query, false)),
+            Err(_) if options.lenient => {
+                let words = lenient_query_words(query);
+                let parsed_query = query_parser
+                    .parse_query(&words)
+                    .map_err(|err| RpcError::query_parse_error(query, &err))?;
+                Ok((parsed_query, true))
+            }
+            Err(err) => Err(RpcError::query_parse_error(query, &err).into()),
         }
-        Ok(())
     }
 
     pub fn query(
-        &mut self,
+        &self,
+        query: &str,
+        options: &QueryOptions,
+        interrupt: &Interrupt,
+    ) -> anyhow::Result<QueryResults> {
+        self.query_with_statistics(query, options, None, interrupt)
+    }
+
+    /// Query the index, scoring the hits with BM25 with the term statistics of several indexes
+    /// instead of the statistics of this index, if given.
+    pub fn query_with_statistics(
+        &self,
         query: &str,
-        limit: u32,
-        snippet_field: Option<String>,
-    ) -> Result<Vec<(f32, NamedFieldDocument, Option<String>)>> {
-        self.ensure_reader()?;
-        self.ensure_query_parser()?;
-        let reader = self.reader.take().unwrap();
-        let query_parser = self.query_parser.take().unwrap();
+        options: &QueryOptions,
+        statistics: Option<&TermStatistics>,
+        interrupt: &Interrupt,
+    ) -> anyhow::Result<QueryResults> {
+        let reader = self.get_reader()?;
         let searcher = reader.searcher();
         let schema = self.index.schema();
 
-        let query = query_parser.parse_query(query)?;
-        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit as usize))?;
-
-        let snippet_generator = match &snippet_field {
-            Some(field_name) => {
-                let field = schema.get_field(&field_name);
-                match field {
-                    Some(field) => Some(SnippetGenerator::create(&searcher, &*query, field)?),
-                    None => None,
-                }
+        let mut parser_options = self.query_parser_options(options)?;
+        // With the statistics of several indexes, the field boosts are applied when scoring
+        // the terms, so the parsed query must not have them.
+        let boosts = match statistics {
+            Some(_) if options.mode == QueryMode::Syntax => {
+                // Still reject boosts of unknown fields.
+                self.default_fields(&parser_options)?;
+                std::mem::take(&mut parser_options.boosts)
             }
+            _ => parser_options.boosts.clone(),
+        };
+        let (query, lenient_fallback) = self.parse_query(query, options, &parser_options)?;
+        let scored_query = match statistics {
+            Some(statistics) => bm25::with_statistics(&schema, &*query, statistics, &boosts)?,
+            None => query.box_clone(),
+        };
+        // Snippets are created for the query without the filters.
+        let filtered_query = apply_filters(&schema, scored_query, &options.filter)?;
+        let cursor = match &options.cursor {
+            Some(cursor) => Some(Cursor::decode(cursor)?),
             None => None,
         };
+        let sort = Sort::new(&schema, &options.sort)?;
+        let limit = options.limit.unwrap_or(10) as usize;
+        let offset = options.offset.unwrap_or(0) as usize;
+        let mut multi_collector = MultiCollector::new();
+        // FruitHandle isn't a public type, so the sorted and unsorted hits have separate handles.
+        let (top_handle, sorted_top_handle) = if sort.is_empty() {
+            let top_docs = TopDocs::with_limit(limit).and_offset(offset);
+            let collector = SearchAfter::new(top_docs, cursor, None);
+            (Some(multi_collector.add_collector(collector)), None)
+        } else {
+            let top_docs = sort.top_docs(&searcher, limit, offset)?;
+            let collector = SearchAfter::new(top_docs, cursor, Some(sort));
+            (None, Some(multi_collector.add_collector(collector)))
+        };
+        let count_handle = multi_collector.add_collector(Count);
+        let collector = interrupt.wrap(multi_collector);
+        let fruits = searcher.search(&filtered_query, &collector);
+        interrupt.check()?;
+        let mut fruits = fruits?;
+        let mut top_docs = vec![];
+        if let Some(top_handle) = top_handle {
+            for (score, doc_address) in top_handle.extract(&mut fruits) {
+                top_docs.push((score, doc_address, Cursor::from_score(score, doc_address)));
+            }
+        }
+        if let Some(sorted_top_handle) = sorted_top_handle {
+            for (value, doc_address) in sorted_top_handle.extract(&mut fruits) {
+                let cursor = Cursor {
+                    values: value.values,
+                    address: doc_address,
+                };
+                top_docs.push((value.score, doc_address, cursor));
+            }
+        }
+        let total_hits = count_handle.extract(&mut fruits);
+
+        let snippets = Snippets::new(
+            &searcher,
+            &*query,
+            &schema,
+            options.snippet_field.as_deref(),
+            &options.highlight_options(),
+        )?;
 
         let mut results = vec![];
-        for (score, doc_address) in top_docs {
-            let retrieved_doc = searcher.doc(doc_address)?;
-            let snippet = match &snippet_generator {
-                Some(generator) => Some(generator.snippet_from_doc(&retrieved_doc).to_html()),
-                None => None,
+        let fields = options.fields.as_deref();
+        // Only load the stored documents if they are needed.
+        let load_docs =
+            !matches!(fields, Some(fields) if fields.is_empty()) || !snippets.is_empty();
+        for (score, doc_address, cursor) in top_docs {
+            let retrieved_doc = if load_docs {
+                searcher.doc(doc_address)?
+            } else {
+                Document::default()
             };
-            results.push((score, schema.to_named_doc(&retrieved_doc), snippet));
+            results.push(QueryHit {
+                score,
+                doc: to_named_doc(&schema, &retrieved_doc, fields),
+                snippet: snippets.snippet(&retrieved_doc),
+                snippets: snippets.snippets(&retrieved_doc),
+                address: doc_address.into(),
+                cursor: cursor.encode(),
+            });
         }
 
-        Ok(results)
+        Ok(QueryResults {
+            total_hits,
+            hits: results,
+            lenient_fallback,
+        })
+    }
+
+    /// Get the document frequencies of the terms of a query.
+    pub fn term_statistics(
+        &self,
+        query: &str,
+        options: &QueryOptions,
+    ) -> anyhow::Result<TermStatistics> {
+        let reader = self.get_reader()?;
+        let parser_options = self.query_parser_options(options)?;
+        let (parsed_query, _) = self.parse_query(query, options, &parser_options)?;
+        Ok(TermStatistics::for_query(
+            &reader.searcher(),
+            &*parsed_query,
+        )?)
     }
 
-    pub fn add_segments(&mut self, segments: Vec<SegmentInfo>) -> Result<()> {
+    pub fn add_segments(&self, segments: Vec<SegmentInfo>) -> Result<()> {
         for segment in segments {
             self.add_segment(&segment.segment_id, segment.max_doc)?;
         }
         Ok(())
     }
 
-    pub fn add_segment(&mut self, uuid_string: &str, max_doc: u32) -> Result<()> {
+    pub fn add_segment(&self, uuid_string: &str, max_doc: u32) -> Result<()> {
         let mut segments = self.index.searchable_segment_metas()?;
         let segment_id = SegmentId::from_uuid_string(uuid_string)
             .map_err(|_err| TantivyError::InvalidArgument("Not a valid UUID string".to_string()))?;
@@ -298,7 +820,7 @@ impl IndexHandle {
         let existing_segment_ids = self.index.searchable_segment_ids()?;
 
         if !existing_segment_ids.contains(&segment_id) {
-            let meta = self.index.new_segment_meta(segment_id, max_doc as u32);
+            let meta = self.index.new_segment_meta(segment_id, max_doc);
             segments.push(meta);
             let schema = self.index.schema();
             // add the counter of docs in segment to the index counter
@@ -310,7 +832,7 @@ impl IndexHandle {
                 payload: None,
                 index_settings: tantivy::IndexSettings::default(),
             };
-            save_metas(&metas, self.index.directory_mut())?;
+            save_metas(&metas, self.index.directory())?;
         } else {
             return Err(TantivyError::InvalidArgument("Segment exists.".to_string()));
         }
@@ -320,28 +842,72 @@ impl IndexHandle {
                 "Adding segment failed.".to_string(),
             ));
         }
-        Ok(())
+        self.reload_reader()
     }
 }
 
+/// The indexed text and JSON fields of a schema, to search terms without a field name in.
+///
+/// Other fields are left out, because the query parser fails on terms that are not
+/// valid values of their type, e.g. words in numeric or facet fields.
+pub fn text_fields(schema: &Schema) -> Vec<Field> {
+    schema
+        .fields()
+        .filter(|(_, entry)| {
+            entry.is_indexed()
+                && matches!(
+                    entry.field_type(),
+                    FieldType::Str(_) | FieldType::JsonObject(_)
+                )
+        })
+        .map(|(field, _)| field)
+        .collect()
+}
+
+/// Index names become directory names in the data dir: they must not be empty, lead out of
+/// it or to hidden files. Percent-encoded names, as in HTTP URLs, are not decoded and rejected.
+fn validate_index_name(name: &str) -> Result<()> {
+    let invalid = name.is_empty()
+        || name.starts_with('.')
+        || name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c == '%' || c.is_control());
+    if invalid {
+        return Err(TantivyError::InvalidArgument(format!(
+            "Invalid index name: {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
 /// Copied from tantivy/src/core/mod.rs
 pub static META_FILEPATH: Lazy<&'static Path> = Lazy::new(|| Path::new("meta.json"));
 
 /// Copied from segment_updater.rs in tantivy.
-fn save_metas(metas: &IndexMeta, directory: &mut dyn Directory) -> Result<()> {
+fn save_metas(metas: &IndexMeta, directory: &dyn Directory) -> Result<()> {
     let mut buffer = serde_json::to_vec_pretty(metas)?;
     writeln!(&mut buffer)?;
     directory.atomic_write(&META_FILEPATH, &buffer[..])?;
     Ok(())
 }
 
+/// The words of a query without any query syntax, to search for them as plain terms.
+fn lenient_query_words(query: &str) -> String {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && !["AND", "OR", "NOT"].contains(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 #[test]
 fn create_empty_indexcatalog() {
     // let base_path = PathBuf::from(r"./test");
     let tmp_dir = tempdir::TempDir::new("test").unwrap();
     let base_path = tmp_dir.path().to_path_buf();
     let catalog = IndexCatalog::new(base_path).unwrap();
-    assert_eq!(catalog.indexes.len(), 0);
+    assert_eq!(catalog.indexes.read().unwrap().len(), 0);
 }
 #[test]
 fn move_segment() {
@@ -350,7 +916,7 @@ fn move_segment() {
     let base_path = tmp_dir.path().to_path_buf();
 
     // create a new index catalog, index catalog is a hashmap with indexname as key and index as value
-    let mut catalog = IndexCatalog::new(base_path.clone()).unwrap();
+    let catalog = IndexCatalog::new(base_path.clone()).unwrap();
 
     // create a new schema with one textfield called "field_str" and build this schema
     let mut schema_builder = Schema::builder();
@@ -358,13 +924,13 @@ fn move_segment() {
     let schema = schema_builder.build();
     // create two new indexes to compare the segment_ids after we call the add_segment method
     catalog
-        .create_index("testindex1".to_string(), schema.clone())
+        .create_index("testindex1".to_string(), schema.clone(), Default::default())
         .unwrap();
     catalog
-        .create_index("testindex2".to_string(), schema.clone())
+        .create_index("testindex2".to_string(), schema.clone(), Default::default())
         .unwrap();
 
-    let handle1 = catalog.get_index(&"testindex1".to_string()).unwrap();
+    let handle1 = catalog.get_index("testindex1").unwrap();
 
     let writer_lock1 = handle1.get_writer().unwrap();
     let mut writer1 = writer_lock1.write().unwrap();
@@ -372,13 +938,13 @@ fn move_segment() {
     // create a new tantivy Document to push this doc to index1
     let mut doc = Document::new();
     doc.add_text(field_str, "sea");
-    writer1.add_document(doc);
+    writer1.add_document(doc).unwrap();
     writer1.commit().unwrap();
 
     let index1 = handle1.index.clone();
     let mut allsegments = index1.searchable_segment_ids().unwrap();
 
-    let handle2 = catalog.get_index(&"testindex2".to_string()).unwrap();
+    let handle2 = catalog.get_index("testindex2").unwrap();
     let index2 = handle2.index.clone();
 
     // get the segment_id for the segment in index1 and copy the files in index2 dir
@@ -426,7 +992,7 @@ fn move_segment() {
             .unwrap()
             .searcher();
 
-        let query_parser = QueryParser::for_index(&index, fields);
+        let query_parser = QueryParser::for_index(index, fields);
 
         // QueryParser may fail if the query is not in the right
         // format. For user facing applications, this can be a problem.
@@ -445,3 +1011,403 @@ fn move_segment() {
         // println!("done");
     }
 }
+
+#[test]
+fn delete_and_update_documents() {
+    let tmp_dir = tempdir::TempDir::new("test").unwrap();
+    let catalog = IndexCatalog::new(tmp_dir.path().to_path_buf()).unwrap();
+
+    let mut schema_builder = Schema::builder();
+    schema_builder.add_text_field("id", STRING | STORED);
+    schema_builder.add_text_field("title", TEXT | STORED);
+    let schema = schema_builder.build();
+    catalog
+        .create_ram_index("testindex".to_string(), schema, Default::default())
+        .unwrap();
+    let handle = catalog.get_index("testindex").unwrap();
+
+    let doc = |id: &str, title: &str| {
+        DocumentInput::Pairs(vec![
+            ("id".to_string(), Value::Str(id.to_string())),
+            ("title".to_string(), Value::Str(title.to_string())),
+        ])
+    };
+    handle
+        .add_documents(
+            &[doc("1", "hello world"), doc("2", "hello moon")],
+            Some(true),
+            OnError::Reject,
+        )
+        .unwrap();
+    assert_eq!(
+        handle
+            .query("hello", &QueryOptions::default(), &Interrupt::none())
+            .unwrap()
+            .hits
+            .len(),
+        2
+    );
+
+    handle
+        .update_documents(
+            "id",
+            &[doc("1", "goodbye world")],
+            Some(true),
+            OnError::Reject,
+        )
+        .unwrap();
+    assert_eq!(
+        handle
+            .query("hello", &QueryOptions::default(), &Interrupt::none())
+            .unwrap()
+            .hits
+            .len(),
+        1
+    );
+    assert_eq!(
+        handle
+            .query("goodbye", &QueryOptions::default(), &Interrupt::none())
+            .unwrap()
+            .hits
+            .len(),
+        1
+    );
+
+    handle
+        .delete_documents("id", &[Value::Str("2".to_string())], Some(true))
+        .unwrap();
+    assert_eq!(
+        handle
+            .query("hello", &QueryOptions::default(), &Interrupt::none())
+            .unwrap()
+            .hits
+            .len(),
+        0
+    );
+    assert_eq!(
+        handle
+            .query("world", &QueryOptions::default(), &Interrupt::none())
+            .unwrap()
+            .hits
+            .len(),
+        1
+    );
+
+    // Tokenized text fields cannot identify documents.
+    let result = handle.delete_documents("title", &[Value::Str("goodbye".to_string())], Some(true));
+    assert!(matches!(result, Err(TantivyError::InvalidArgument(_))));
+    let result = handle.update_documents(
+        "title",
+        &[doc("3", "goodbye world")],
+        Some(true),
+        OnError::Reject,
+    );
+    assert!(matches!(result, Err(TantivyError::InvalidArgument(_))));
+    assert_eq!(
+        handle
+            .query("goodbye", &QueryOptions::default(), &Interrupt::none())
+            .unwrap()
+            .hits
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn query_merged_indexes() {
+    let tmp_dir = tempdir::TempDir::new("test").unwrap();
+    let catalog = IndexCatalog::new(tmp_dir.path().to_path_buf()).unwrap();
+
+    let titles = [
+        ("a", vec!["hello world", "foo bar"]),
+        (
+            "b",
+            vec!["hello world", "hello world", "foo bar", "foo bar"],
+        ),
+    ];
+    for (name, titles) in titles.iter() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("title", TEXT | STORED);
+        let schema = schema_builder.build();
+        catalog
+            .create_ram_index(name.to_string(), schema, Default::default())
+            .unwrap();
+        let docs: Vec<DocumentInput> = titles
+            .iter()
+            .map(|title| serde_json::from_value(serde_json::json!({ "title": title })).unwrap())
+            .collect();
+        let handle = catalog.get_index(name).unwrap();
+        handle
+            .add_documents(&docs, Some(true), OnError::Reject)
+            .unwrap();
+    }
+
+    let indexes = vec!["a".to_string(), "b".to_string(), "missing".to_string()];
+    let results = catalog
+        .query_merged("hello", &indexes, &Default::default(), &Interrupt::none())
+        .unwrap();
+    assert_eq!(results.total_hits, 3);
+    assert_eq!(results.hits.len(), 3);
+    assert_eq!(results.hits[0].0, "a");
+    // With the statistics of both indexes, equal documents get equal scores.
+    let scores: Vec<f32> = results.hits.iter().map(|(_, hit)| hit.score).collect();
+    assert!((scores[0] - scores[2]).abs() < 1e-4);
+
+    let options = QueryOptions {
+        limit: Some(1),
+        offset: Some(2),
+        ..Default::default()
+    };
+    let results = catalog
+        .query_merged("hello", &indexes, &options, &Interrupt::none())
+        .unwrap();
+    assert_eq!(results.hits.len(), 1);
+    assert_eq!(results.hits[0].0, "b");
+
+    // "hello" is rare in c and common in d, so the long document of c has the higher
+    // score in its own index. Over both indexes, the short documents of d rank first.
+    let texts = [
+        (
+            "c",
+            vec!["hello world foo bar baz", "x", "x", "x", "x", "x", "x"],
+        ),
+        ("d", vec!["hello", "hello"]),
+    ];
+    for (name, texts) in texts.iter() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("title", TEXT | STORED);
+        catalog
+            .create_ram_index(name.to_string(), schema_builder.build(), Default::default())
+            .unwrap();
+        let docs: Vec<DocumentInput> = texts
+            .iter()
+            .map(|title| serde_json::from_value(serde_json::json!({ "title": title })).unwrap())
+            .collect();
+        let handle = catalog.get_index(name).unwrap();
+        handle
+            .add_documents(&docs, Some(true), OnError::Reject)
+            .unwrap();
+    }
+    let indexes = vec!["c".to_string(), "d".to_string()];
+    let mut local_hits: Vec<(String, f32)> = catalog
+        .query_multi(
+            "hello",
+            &indexes,
+            &Default::default(),
+            &Default::default(),
+            &Interrupt::none(),
+        )
+        .unwrap()
+        .into_iter()
+        .flat_map(|(name, results)| {
+            results
+                .hits
+                .into_iter()
+                .map(move |hit| (name.clone(), hit.score))
+        })
+        .collect();
+    local_hits.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+    let local_order: Vec<&str> = local_hits.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(local_order, vec!["c", "d", "d"]);
+    let results = catalog
+        .query_merged("hello", &indexes, &Default::default(), &Interrupt::none())
+        .unwrap();
+    let merged_order: Vec<&str> = results.hits.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(merged_order, vec!["d", "d", "c"]);
+}
+
+#[test]
+fn query_fields() {
+    let mut schema_builder = Schema::builder();
+    schema_builder.add_text_field("id", STRING | STORED);
+    schema_builder.add_text_field("body", TEXT | STORED);
+    let fixture = crate::fixture::Fixture::new(schema_builder.build());
+    fixture.add(serde_json::json!([{ "id": "1", "body": "hello world" }]));
+    let handle = &fixture.handle;
+
+    let options = QueryOptions {
+        fields: Some(vec!["id".to_string()]),
+        ..Default::default()
+    };
+    let results = handle.query("hello", &options, &Interrupt::none()).unwrap();
+    let doc = &results.hits[0].doc.0;
+    assert_eq!(doc.len(), 1);
+    assert!(doc.contains_key("id"));
+
+    let options = QueryOptions {
+        fields: Some(vec![]),
+        ..Default::default()
+    };
+    let results = handle.query("hello", &options, &Interrupt::none()).unwrap();
+    assert!(results.hits[0].doc.0.is_empty());
+    assert_eq!(results.hits[0].address.doc_id, 0);
+}
+
+#[test]
+fn query_parser_options() {
+    let mut schema_builder = Schema::builder();
+    schema_builder.add_text_field("id", STRING | STORED);
+    schema_builder.add_text_field("title", TEXT | STORED);
+    schema_builder.add_text_field("body", TEXT | STORED);
+    schema_builder.add_u64_field("size", INDEXED);
+    let schema = schema_builder.build();
+    let mut options = IndexOptions::default();
+    options.query_parser.default_fields = Some(vec!["title".to_string()]);
+    let fixture = crate::fixture::Fixture::with_options(schema.clone(), options.clone());
+    let handle = &fixture.handle;
+    let docs = serde_json::json!([
+        { "id": "1", "title": "hello", "body": "world", "size": 1 },
+        { "id": "2", "title": "world", "body": "hello", "size": 2 },
+    ]);
+    fixture.add(docs.clone());
+
+    let ids = |query: &str, options: &QueryOptions| {
+        crate::fixture::ids(&handle.query(query, options, &Interrupt::none()).unwrap())
+    };
+
+    // Only the default field of the index is searched.
+    assert_eq!(ids("hello", &QueryOptions::default()), vec!["1"]);
+
+    // Per query default fields and boosts.
+    let query_options = QueryOptions {
+        default_fields: Some(vec!["title".to_string(), "body".to_string()]),
+        boosts: Some(vec![("body".to_string(), 10.0)].into_iter().collect()),
+        ..Default::default()
+    };
+    assert_eq!(ids("hello", &query_options), vec!["2", "1"]);
+
+    // Conjunction requires all terms.
+    let query_options = QueryOptions {
+        default_fields: Some(vec!["title".to_string(), "body".to_string()]),
+        ..Default::default()
+    };
+    assert_eq!(ids("hello world", &query_options).len(), 2);
+    let query_options = QueryOptions {
+        default_fields: Some(vec!["title".to_string()]),
+        conjunction: Some(true),
+        ..Default::default()
+    };
+    assert!(ids("hello world", &query_options).is_empty());
+
+    // Unknown fields are rejected.
+    let mut invalid_options = options.clone();
+    invalid_options.required_fields = vec!["missing".to_string()];
+    let result = handle.set_options(invalid_options);
+    assert!(matches!(result, Err(TantivyError::InvalidArgument(_))));
+    options
+        .query_parser
+        .boosts
+        .insert("missing".to_string(), 2.0);
+    assert!(handle.set_options(options).is_err());
+    assert_eq!(
+        handle.options().unwrap().query_parser.default_fields,
+        Some(vec!["title".to_string()])
+    );
+
+    // Without default fields, all text fields are searched, but not the numeric field.
+    let fixture = crate::fixture::Fixture::new(schema);
+    fixture.add(docs);
+    let results = fixture
+        .handle
+        .query("hello", &QueryOptions::default(), &Interrupt::none())
+        .unwrap();
+    assert_eq!(results.hits.len(), 2);
+}
+
+#[test]
+fn query_lenient() {
+    let mut schema_builder = Schema::builder();
+    schema_builder.add_text_field("body", TEXT | STORED);
+    let fixture = crate::fixture::Fixture::new(schema_builder.build());
+    fixture.add(serde_json::json!([{ "body": "hello world" }]));
+    let handle = &fixture.handle;
+
+    let strict = QueryOptions::default();
+    let lenient = QueryOptions {
+        lenient: true,
+        ..Default::default()
+    };
+    for query in &["hello AND (", "\"hello world", "missing:hello"] {
+        assert!(handle.query(query, &strict, &Interrupt::none()).is_err());
+        let results = handle.query(query, &lenient, &Interrupt::none()).unwrap();
+        assert!(results.lenient_fallback);
+        assert_eq!(results.total_hits, 1);
+    }
+    let results = handle.query("hello", &lenient, &Interrupt::none()).unwrap();
+    assert!(!results.lenient_fallback);
+    assert_eq!(lenient_query_words("a AND (b OR \"c"), "a b c");
+}
+
+#[test]
+fn list_indexes_with_schemas() {
+    let tmp_dir = tempdir::TempDir::new("test").unwrap();
+    let catalog = IndexCatalog::new(tmp_dir.path().to_path_buf()).unwrap();
+
+    let mut schema_builder = Schema::builder();
+    schema_builder.add_text_field("id", STRING | STORED);
+    schema_builder.add_text_field("title", TEXT | STORED);
+    schema_builder.add_u64_field("size", FAST);
+    let schema = schema_builder.build();
+    catalog
+        .create_index("b".to_string(), schema.clone(), Default::default())
+        .unwrap();
+    catalog
+        .create_ram_index("a".to_string(), schema, Default::default())
+        .unwrap();
+
+    let infos = catalog.list_indexes().unwrap();
+    let names: Vec<&str> = infos.iter().map(|info| info.name.as_str()).collect();
+    assert_eq!(names, vec!["a", "b"]);
+    assert_eq!(infos[0].storage, Storage::Ram);
+    assert_eq!(infos[1].storage, Storage::Disk);
+
+    let info = catalog.index_info("b").unwrap();
+    assert_eq!(info.tokenizers.get("id").unwrap(), "raw");
+    assert_eq!(info.tokenizers.get("title").unwrap(), "default");
+    assert!(!info.tokenizers.contains_key("size"));
+    let json = serde_json::to_value(&info).unwrap();
+    assert_eq!(json["storage"], "disk");
+    assert_eq!(json["schema"][2]["name"], "size");
+    assert_eq!(json["schema"][2]["options"]["fast"], "single");
+
+    // Indexes on disk are listed again after a restart.
+    drop(catalog);
+    let catalog = IndexCatalog::new(tmp_dir.path().to_path_buf()).unwrap();
+    let infos = catalog.list_indexes().unwrap();
+    assert_eq!(infos.len(), 1);
+    assert_eq!(infos[0].name, "b");
+    assert!(catalog.index_info("a").is_err());
+}
+
+#[test]
+fn index_names() {
+    let tmp_dir = tempdir::TempDir::new("test").unwrap();
+    let data_dir = tmp_dir.path().join("data");
+    let catalog = IndexCatalog::new(data_dir.clone()).unwrap();
+    let schema = Schema::builder().build();
+
+    for name in &["books", "books-2021", "my_index"] {
+        catalog
+            .create_index(name.to_string(), schema.clone(), Default::default())
+            .unwrap();
+        assert!(catalog.get_index(name).is_ok());
+    }
+    for name in &["", "..", ".hidden", "a/b", "a\\b", "%2e%2e", "a\nb"] {
+        let err = catalog
+            .create_index(name.to_string(), schema.clone(), Default::default())
+            .unwrap_err();
+        assert!(
+            matches!(err, TantivyError::InvalidArgument(_)),
+            "{:?}",
+            name
+        );
+        assert!(catalog
+            .create_ram_index(name.to_string(), schema.clone(), Default::default())
+            .is_err());
+        assert!(catalog.get_index(name).is_err());
+        assert!(catalog.delete_index(name.to_string()).is_err());
+    }
+    // Names that point at the data dir or its parent do not delete anything.
+    assert!(data_dir.join("books").exists());
+    assert!(tmp_dir.path().exists());
+}
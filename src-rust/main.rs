@@ -15,32 +15,85 @@ use std::path::PathBuf;
 /// - use index.directory().atomic_write() to write a new meta.json
 /// - this should automatically reload the Reader (if it has a ReloadPolicy Oncommit)
 /// - for safety, all index writers should be destroyed before (but there would be none usually - only for merges maybe)
+mod aggregation;
+mod bm25;
+mod collector;
+mod commit;
+mod document;
+mod error;
+mod facet;
+mod filter;
+#[cfg(test)]
+mod fixture;
+mod fuzzy;
 mod handles;
+#[cfg(feature = "http")]
+mod http;
 mod index;
+mod options;
 mod query;
 mod rpc;
 mod search;
+mod snippet;
+mod sort;
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("USAGE: {} BASE_PATH", &args[0]);
-        ::std::process::exit(1);
+    let allow_remote = env::args().any(|arg| arg == "--allow-remote");
+    let args: Vec<String> = env::args().filter(|arg| arg != "--allow-remote").collect();
+    let (mode, address) = match args.len() {
+        2 => ("", ""),
+        4 if args[2] == "--listen" || args[2] == "--http" => (args[2].as_str(), args[3].as_str()),
+        _ => {
+            eprintln!(
+                "USAGE: {} BASE_PATH [--listen <UNIX_PATH|HOST:PORT>] [--http <HOST:PORT>] [--allow-remote]",
+                &args[0]
+            );
+            ::std::process::exit(1);
+        }
+    };
+    let tcp = mode == "--http" || (mode == "--listen" && !rpc::is_unix_address(address));
+    if tcp && !allow_remote {
+        rpc::ensure_loopback(address)?;
     }
 
     let base_path = PathBuf::from(&args[1]);
     let catalog = IndexCatalog::new(base_path)?;
+    if mode == "--http" {
+        return serve_http(catalog, address);
+    }
     let mut rpc = Rpc::new(catalog);
     rpc.at("create_index", &handles::create_index);
     rpc.at("create_ram_index", &handles::create_ram_index);
     rpc.at("index_exists", &handles::index_exists);
+    rpc.at("get_schema", &handles::get_schema);
+    rpc.at("list_indexes", &handles::list_indexes);
+    rpc.at("set_index_options", &handles::set_index_options);
     rpc.at("add_documents", &handles::add_documents);
+    rpc.at("delete_documents", &handles::delete_documents);
+    rpc.at("update_documents", &handles::update_documents);
+    rpc.at("commit", &handles::commit);
+    rpc.at("prepare_commit", &handles::prepare_commit);
+    rpc.at("rollback", &handles::rollback);
     rpc.at("query", &handles::query);
     rpc.at("query_json", &query::query_json);
     rpc.at("query_multi", &handles::query_multi);
     rpc.at("add_segment", &handles::add_segment);
     rpc.at("add_segments", &handles::add_segments);
     rpc.at("delete_index", &handles::delete_index);
-    rpc.stdio_loop();
+    match mode {
+        "--listen" => rpc.listen(address)?,
+        _ => rpc.stdio_loop(),
+    }
     Ok(())
 }
+
+#[cfg(feature = "http")]
+fn serve_http(catalog: IndexCatalog, address: &str) -> io::Result<()> {
+    http::serve(catalog, address, rpc::default_num_workers())
+}
+
+#[cfg(not(feature = "http"))]
+fn serve_http(_catalog: IndexCatalog, _address: &str) -> io::Result<()> {
+    eprintln!("The HTTP server is not available, build with --features http.");
+    ::std::process::exit(1);
+}
@@ -0,0 +1,127 @@
+use crate::commit::CommitPolicy;
+use crate::filter::Filter;
+use crate::fuzzy::QueryMode;
+use crate::snippet::SnippetFormat;
+use crate::sort::SortBy;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tantivy::directory::error::OpenReadError;
+use tantivy::{Directory, Result};
+
+/// Index options
+///
+/// Settings of an index that are not part of the tantivy schema.
+/// They are stored next to the tantivy meta.json in the index directory.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IndexOptions {
+    #[serde(default)]
+    pub commit_policy: CommitPolicy,
+    /// Fields that every added document has to contain.
+    #[serde(default)]
+    pub required_fields: Vec<String>,
+    /// Defaults for parsing string queries.
+    #[serde(default)]
+    pub query_parser: QueryParserOptions,
+}
+
+/// Settings of the parser for string queries.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct QueryParserOptions {
+    /// Fields to search for terms without a field name. Default: all indexed text and JSON fields.
+    pub default_fields: Option<Vec<String>>,
+    /// Factors for the scores of matches in a field, by field name.
+    #[serde(default)]
+    pub boosts: HashMap<String, f32>,
+    /// Match only documents that contain all terms, instead of any term.
+    #[serde(default)]
+    pub conjunction: bool,
+}
+
+impl QueryParserOptions {
+    /// Apply the overrides of a single query.
+    pub fn with_overrides(&self, options: &QueryOptions) -> QueryParserOptions {
+        let mut merged = self.clone();
+        if let Some(default_fields) = &options.default_fields {
+            merged.default_fields = Some(default_fields.clone());
+        }
+        if let Some(boosts) = &options.boosts {
+            merged.boosts.extend(boosts.clone());
+        }
+        if let Some(conjunction) = options.conjunction {
+            merged.conjunction = conjunction;
+        }
+        merged
+    }
+}
+
+/// Query options
+///
+/// Options of a single query, sent by clients together with the query.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Max number of hits to return.
+    pub limit: Option<u32>,
+    /// Number of hits to skip.
+    pub offset: Option<u32>,
+    /// Return the hits after this cursor, taken from a hit of a previous response.
+    pub cursor: Option<String>,
+    /// Field to create an HTML snippet with highlighted keywords for.
+    pub snippet_field: Option<String>,
+    /// Fields to create snippets for, returned by field name.
+    #[serde(default)]
+    pub snippet_fields: Vec<String>,
+    /// Max length of the snippets (default 150 characters).
+    pub snippet_max_chars: Option<usize>,
+    /// Format of the snippets of `snippet_fields` (default HTML).
+    #[serde(default)]
+    pub snippet_format: SnippetFormat,
+    /// Start and end tag to wrap highlighted keywords in HTML snippets (default `<b>`, `</b>`).
+    pub snippet_tags: Option<(String, String)>,
+    /// Fail with a timeout error if the search takes longer.
+    pub timeout_ms: Option<u64>,
+    /// Stored fields to return. An empty list returns only scores and doc addresses.
+    /// Default: all stored fields.
+    pub fields: Option<Vec<String>>,
+    /// Override the default fields of the query parser options of the index.
+    pub default_fields: Option<Vec<String>>,
+    /// Boosts in addition to the boosts of the query parser options of the index.
+    pub boosts: Option<HashMap<String, f32>>,
+    /// Override the conjunction setting of the query parser options of the index.
+    pub conjunction: Option<bool>,
+    /// If the query has a syntax error, search for its words instead of failing.
+    #[serde(default)]
+    pub lenient: bool,
+    /// Parse the query (default), or search for its words with prefix or fuzzy matching.
+    #[serde(default)]
+    pub mode: QueryMode,
+    /// Max number of typos per word in the prefix and fuzzy modes.
+    pub edit_distance: Option<u8>,
+    /// Fast fields to sort the hits by, instead of by score.
+    #[serde(default)]
+    pub sort: Vec<SortBy>,
+    /// Constraints on the hits that don't change their scores.
+    #[serde(default)]
+    pub filter: Vec<Filter>,
+}
+
+pub static OPTIONS_FILEPATH: Lazy<&'static Path> = Lazy::new(|| Path::new("sonar-options.json"));
+
+impl IndexOptions {
+    /// Load the options from an index directory. Returns the default options if
+    /// none were saved yet.
+    pub fn load(directory: &dyn Directory) -> Result<IndexOptions> {
+        match directory.atomic_read(&OPTIONS_FILEPATH) {
+            Ok(buffer) => Ok(serde_json::from_slice(&buffer[..])?),
+            Err(OpenReadError::FileDoesNotExist(_)) => Ok(IndexOptions::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self, directory: &dyn Directory) -> Result<()> {
+        let buffer = serde_json::to_vec_pretty(self)?;
+        directory.atomic_write(&OPTIONS_FILEPATH, &buffer[..])?;
+        Ok(())
+    }
+}
@@ -1,28 +1,99 @@
+use crate::collector::Interrupt;
+use crate::error::{ErrorCode, RpcError};
 use crate::handles::Res;
 use crate::index::IndexCatalog;
 use crate::rpc::Request;
-use crate::search::search_index;
+use crate::search::{search_index, ResultOptions, SearchOptions};
 use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
 use toshi_types::Search;
 
 #[derive(Deserialize)]
 struct QueryRequest {
     index: String,
     search: Search,
+    #[serde(flatten)]
+    result_options: ResultOptions,
+    #[serde(flatten)]
+    options: SearchOptions,
+    /// The fields that none of the options take. serde can't deny unknown fields
+    /// of flattened structs, so they are collected here.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, JsonValue>,
 }
 
-pub fn query_json(catalog: &mut IndexCatalog, request: &Request) -> Result<Res, anyhow::Error> {
+pub fn query_json(catalog: &IndexCatalog, request: &Request) -> Result<Res, anyhow::Error> {
+    let cancel_token = request.cancel_token();
     let request: QueryRequest = request.message()?;
+    if let Some(field) = request.unknown.keys().next() {
+        let message = format!("Unknown option of query_json: {}", field);
+        return Err(RpcError::new(ErrorCode::InvalidArgument, message).into());
+    }
+    let interrupt = Interrupt::new(cancel_token, request.options.timeout_ms);
     let handle = catalog.get_index(&request.index)?;
     let reader = handle.get_reader()?;
 
-    let results = search_index(&handle.index, &reader, request.search);
-    match results {
-        Ok(results) => {
-            // let value: Value = Value::from(results);
-            let string = serde_json::to_string(&results).unwrap();
-            Ok(Res::Json(string))
+    let results = search_index(
+        &handle.index,
+        &reader,
+        request.search,
+        &request.options,
+        &request.result_options,
+        &interrupt,
+    );
+    interrupt.check()?;
+    let string = serde_json::to_string(&results?)?;
+    Ok(Res::Json(string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::schema::{Schema, STORED, TEXT};
+
+    #[test]
+    fn query_json_options() {
+        let tmp_dir = tempdir::TempDir::new("test").unwrap();
+        let catalog = IndexCatalog::new(tmp_dir.path().to_path_buf()).unwrap();
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("body", TEXT | STORED);
+        catalog
+            .create_ram_index(
+                "test".to_string(),
+                schema_builder.build(),
+                Default::default(),
+            )
+            .unwrap();
+
+        let query = |options: serde_json::Value| {
+            let mut msg = serde_json::json!({
+                "index": "test",
+                "search": { "query": { "raw": "x" }, "limit": 10 },
+            });
+            msg.as_object_mut()
+                .unwrap()
+                .extend(options.as_object().unwrap().clone());
+            let request: Request = serde_json::from_value(serde_json::json!({
+                "id": 1,
+                "method": "query_json",
+                "msg": msg,
+            }))
+            .unwrap();
+            query_json(&catalog, &request).map_err(RpcError::from)
+        };
+
+        assert!(
+            query(serde_json::json!({ "offset": 1, "fields": [], "timeout_ms": 1000 })).is_ok()
+        );
+        // Options of string queries don't apply to Toshi search requests.
+        for options in &[
+            serde_json::json!({ "snippet_field": "body" }),
+            serde_json::json!({ "boosts": { "body": 2 } }),
+            serde_json::json!({ "limit": 5 }),
+        ] {
+            let err = query(options.clone()).err().unwrap();
+            assert_eq!(err.code, ErrorCode::InvalidArgument, "{}", options);
         }
-        Err(err) => Err(err.into()),
     }
 }
@@ -1,13 +1,22 @@
 extern crate serde_json;
 extern crate varinteger;
 
+use crate::error::{ErrorCode, RpcError};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::io::{self, BufRead};
-use std::rc::Rc;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message<T>
@@ -23,9 +32,16 @@ pub struct Request {
     id: i64,
     method: String,
     msg: serde_json::Value,
+    #[serde(skip)]
+    cancel_token: CancelToken,
 }
 
 impl Request {
+    /// The token is set once the client sends a `cancel` request for this request.
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel_token
+    }
+
     pub fn message<T>(&self) -> Result<T, serde_json::Error>
     where
         T: DeserializeOwned,
@@ -39,10 +55,24 @@ impl Request {
             id: 0,
             method: "".to_string(),
             msg: serde_json::Value::Null,
+            cancel_token: CancelToken::default(),
         }
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response<T>
 where
@@ -50,16 +80,16 @@ where
 {
     id: i64,
     msg: Option<Box<T>>,
-    err: Option<String>,
+    err: Option<RpcError>,
 }
 
 impl<T> Response<T>
 where
     T: Any + Serialize + Debug,
 {
-    pub fn error(request: Request, error: String) -> Response<T> {
+    pub fn error(request: Request, error: RpcError) -> Response<T> {
         Response {
-            id: request.id * -1,
+            id: -request.id,
             msg: None,
             err: Some(error),
         }
@@ -67,7 +97,7 @@ where
 
     pub fn ok(request: Request, msg: T) -> Response<T> {
         Response {
-            id: request.id * -1,
+            id: -request.id,
             msg: Some(Box::new(msg)),
             err: None,
         }
@@ -88,19 +118,23 @@ where
     }
 }
 
+pub type Method<State, T, E> = dyn Fn(&State, &Request) -> Result<T, E> + Send + Sync;
+
 pub struct Rpc<State, T, E>
 where
+    State: 'static,
     T: Any + Serialize + Debug,
-    E: std::string::ToString,
+    E: Into<RpcError> + 'static,
 {
     state: State,
-    methods: HashMap<String, Rc<dyn Fn(&mut State, &Request) -> Result<T, E>>>,
+    methods: HashMap<String, &'static Method<State, T, E>>,
 }
 
 impl<State, T, E> Rpc<State, T, E>
 where
+    State: Send + Sync + 'static,
     T: Any + Serialize + Debug,
-    E: std::string::ToString,
+    E: Into<RpcError> + 'static,
 {
     pub fn new(state: State) -> Rpc<State, T, E> {
         Rpc {
@@ -109,73 +143,428 @@ where
         }
     }
 
-    pub fn at(
-        &mut self,
-        name: &str,
-        method: &'static dyn Fn(&mut State, &Request) -> Result<T, E>,
-    ) {
-        let rc_method = Rc::new(method);
-        self.methods.insert(name.to_string(), rc_method);
+    pub fn at(&mut self, name: &str, method: &'static Method<State, T, E>) {
+        self.methods.insert(name.to_string(), method);
     }
 
-    pub fn stdio_loop(&mut self) {
+    pub fn stdio_loop(self) {
+        let dispatcher = self.start(default_num_workers());
         let stdin = io::stdin();
-        // let mut stdout = io::stdout();
-        let handshake = hello();
-        self.send(Message::Request(handshake));
+        dispatcher.serve(stdin.lock(), io::stdout());
+    }
 
-        for line in stdin.lock().lines() {
-            let line = line.expect("Could not read line from standard in");
-            self.recv(line)
+    /// Serve clients that connect to a unix socket path or a `host:port` TCP address.
+    ///
+    /// All connections share the worker threads and the state.
+    pub fn listen(self, address: &str) -> io::Result<()> {
+        let dispatcher = Arc::new(self.start(default_num_workers()));
+        if is_unix_address(address) {
+            listen_unix(dispatcher, Path::new(address))
+        } else {
+            listen_tcp(dispatcher, address)
         }
     }
 
-    pub fn recv(&mut self, line: String) {
-        // eprintln!("RECV: {}", line);
-        let parsed_request = self.parse_json(&line);
-        let response = match parsed_request {
-            Ok(request) => self.onrequest(request),
-            Err(err) => Response::error(Request::empty(), err.to_string()),
-        };
-        self.send(Message::Response(response))
+    /// Start the worker threads that handle the requests.
+    pub fn start(self, num_workers: usize) -> Dispatcher {
+        let rpc = Arc::new(self);
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..num_workers {
+            let rpc = Arc::clone(&rpc);
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || rpc.work(receiver));
+        }
+        Dispatcher {
+            jobs: Mutex::new(sender),
+        }
     }
 
-    pub fn send(&self, msg: Message<T>)
-    where
-        T: Serialize + Debug,
-    {
-        let json = match msg {
-            Message::Request(ref req) => serde_json::to_string(&req),
-            Message::Response(ref res) => serde_json::to_string(&res),
-        };
-        match json {
-            Ok(str) => println!("{}", str),
-            Err(_err) => eprintln!("Could not serialize message."),
+    fn work(&self, receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = match receiver.lock() {
+                Ok(receiver) => match receiver.recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                },
+                Err(_) => return,
+            };
+            let Job {
+                request,
+                output,
+                pending,
+            } = job;
+            let id = request.id;
+            // A panicking handler is answered with an error, so that the worker keeps running.
+            let response = panic::catch_unwind(AssertUnwindSafe(|| self.onrequest(request)))
+                .unwrap_or_else(|panic| {
+                    let request = Request {
+                        id,
+                        ..Request::empty()
+                    };
+                    Response::error(request, panic_error(panic))
+                });
+            send(&output, &Message::Response(response));
+            pending.finish(id);
         }
     }
 
-    fn onrequest(&mut self, request: Request) -> Response<T> {
+    fn onrequest(&self, request: Request) -> Response<T> {
+        if request.cancel_token.is_cancelled() {
+            return Response::error(request, RpcError::cancelled());
+        }
         if let Some(method) = self.methods.get(&request.method) {
-            let response = method(&mut self.state, &request);
+            let response = method(&self.state, &request);
             match response {
                 Ok(response) => Response::ok(request, response),
-                Err(err) => Response::error(request, err.to_string()),
+                Err(err) => Response::error(request, err.into()),
             }
         } else {
-            Response::error(request, "Method not found.".to_string())
+            let error = RpcError::method_not_found(&request.method);
+            Response::error(request, error)
         }
     }
+}
 
-    fn parse_json(&mut self, json: &str) -> serde_json::Result<Request> {
-        let request: serde_json::Result<Request> = serde_json::from_str(json);
-        request
+fn panic_error(panic: Box<dyn Any + Send>) -> RpcError {
+    let message = if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_string()
+    };
+    RpcError::new(
+        ErrorCode::Internal,
+        format!("Request handler panicked: {}", message),
+    )
+}
+
+type Output = Arc<Mutex<dyn Write + Send>>;
+
+struct Job {
+    request: Request,
+    output: Output,
+    pending: Arc<Pending>,
+}
+
+/// Dispatcher
+///
+/// Reads requests from a connection and passes them on to the worker threads.
+/// Requests are handled concurrently, so responses are sent in the order in
+/// which they are ready, not in the order of the requests.
+///
+/// `cancel` requests are answered by the dispatcher itself: They set the cancel token
+/// of the request with the id in the message, if it was not answered yet.
+pub struct Dispatcher {
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl Dispatcher {
+    /// Serve requests from `reader` until it is closed, and write the responses to `writer`.
+    ///
+    /// Returns once all requests have been answered.
+    pub fn serve<R, W>(&self, reader: R, writer: W)
+    where
+        R: BufRead,
+        W: Write + Send + 'static,
+    {
+        let output: Output = Arc::new(Mutex::new(writer));
+        let pending = Arc::new(Pending::default());
+        let handshake = hello();
+        send::<()>(&output, &Message::Request(handshake));
+
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    eprintln!("Could not read line: {}", err);
+                    break;
+                }
+            };
+            // eprintln!("RECV: {}", line);
+            match parse_json(&line) {
+                Ok(request) if request.method == "cancel" => {
+                    let response = match request.message::<i64>() {
+                        Ok(id) => Response::ok(request, pending.cancel(id)),
+                        Err(err) => {
+                            let error = RpcError::new(ErrorCode::InvalidRequest, err);
+                            Response::error(request, error)
+                        }
+                    };
+                    send(&output, &Message::Response(response));
+                }
+                Ok(request) => {
+                    pending.add(request.id, request.cancel_token.clone());
+                    let id = request.id;
+                    let job = Job {
+                        request,
+                        output: Arc::clone(&output),
+                        pending: Arc::clone(&pending),
+                    };
+                    let sent = match self.jobs.lock() {
+                        Ok(jobs) => jobs.send(job).is_ok(),
+                        Err(_) => false,
+                    };
+                    if !sent {
+                        eprintln!("Could not dispatch request: Workers are gone.");
+                        pending.finish(id);
+                    }
+                }
+                Err(err) => {
+                    let error = RpcError::new(ErrorCode::InvalidRequest, err);
+                    let response = Response::<()>::error(Request::empty(), error);
+                    send(&output, &Message::Response(response));
+                }
+            }
+        }
+        pending.wait();
     }
 }
 
+/// The requests of a connection that were not answered yet.
+#[derive(Default)]
+struct Pending {
+    requests: Mutex<PendingRequests>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct PendingRequests {
+    count: usize,
+    cancel_tokens: HashMap<i64, CancelToken>,
+}
+
+impl Pending {
+    fn add(&self, id: i64, cancel_token: CancelToken) {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.count += 1;
+            requests.cancel_tokens.insert(id, cancel_token);
+        }
+    }
+
+    fn finish(&self, id: i64) {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.count -= 1;
+            requests.cancel_tokens.remove(&id);
+            if requests.count == 0 {
+                self.done.notify_all();
+            }
+        }
+    }
+
+    /// Cancel a request. Returns false if there is no pending request with this id.
+    fn cancel(&self, id: i64) -> bool {
+        match self.requests.lock() {
+            Ok(requests) => match requests.cancel_tokens.get(&id) {
+                Some(cancel_token) => {
+                    cancel_token.cancel();
+                    true
+                }
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn wait(&self) {
+        if let Ok(mut requests) = self.requests.lock() {
+            while requests.count > 0 {
+                requests = match self.done.wait(requests) {
+                    Ok(requests) => requests,
+                    Err(_) => return,
+                };
+            }
+        }
+    }
+}
+
+pub fn is_unix_address(address: &str) -> bool {
+    address.contains('/') || address.ends_with(".sock")
+}
+
+/// Fail unless all addresses a `host:port` address resolves to are loopback addresses.
+///
+/// The servers have no authentication, so they must not be reachable from other hosts
+/// unless this is explicitly wanted.
+pub fn ensure_loopback(address: &str) -> io::Result<()> {
+    for socket_address in address.to_socket_addrs()? {
+        if !socket_address.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Not a loopback address, pass --allow-remote to listen on it: {}",
+                    socket_address
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn listen_tcp(dispatcher: Arc<Dispatcher>, address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Could not accept connection: {}", err);
+                continue;
+            }
+        };
+        let reader = BufReader::new(stream.try_clone()?);
+        let dispatcher = Arc::clone(&dispatcher);
+        thread::spawn(move || dispatcher.serve(reader, stream));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn listen_unix(dispatcher: Arc<Dispatcher>, path: &Path) -> io::Result<()> {
+    // Remove the socket file of a previous server, unless it is still running.
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Another server is listening on {}", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    eprintln!("Listening on {}", path.display());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Could not accept connection: {}", err);
+                continue;
+            }
+        };
+        let reader = BufReader::new(stream.try_clone()?);
+        let dispatcher = Arc::clone(&dispatcher);
+        thread::spawn(move || dispatcher.serve(reader, stream));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_dispatcher: Arc<Dispatcher>, path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Unix sockets are not supported: {}", path.display()),
+    ))
+}
+
+pub fn default_num_workers() -> usize {
+    thread::available_parallelism()
+        .map(|num| num.get())
+        .unwrap_or(4)
+}
+
+fn send<T>(output: &Output, msg: &Message<T>)
+where
+    T: Serialize + Debug,
+{
+    let json = match msg {
+        Message::Request(ref req) => serde_json::to_string(&req),
+        Message::Response(ref res) => serde_json::to_string(&res),
+    };
+    let json = match json {
+        Ok(json) => json,
+        Err(_err) => {
+            eprintln!("Could not serialize message.");
+            return;
+        }
+    };
+    if let Ok(mut output) = output.lock() {
+        let result = writeln!(output, "{}", json).and_then(|_| output.flush());
+        if let Err(err) = result {
+            eprintln!("Could not send message: {}", err);
+        }
+    }
+}
+
+fn parse_json(json: &str) -> serde_json::Result<Request> {
+    let request: serde_json::Result<Request> = serde_json::from_str(json);
+    request
+}
+
 fn hello() -> Request {
     Request {
         id: 0,
         method: "hello".to_string(),
         msg: serde_json::Value::Null,
+        cancel_token: CancelToken::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer whose output can be read after it was moved into the dispatcher.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn echo(_state: &(), request: &Request) -> Result<serde_json::Value, RpcError> {
+        Ok(request.msg.clone())
+    }
+
+    fn fail(_state: &(), _request: &Request) -> Result<serde_json::Value, RpcError> {
+        panic!("boom")
+    }
+
+    #[test]
+    fn worker_survives_panic() {
+        let mut rpc = Rpc::new(());
+        rpc.at("echo", &echo);
+        rpc.at("fail", &fail);
+        // With a single worker, the second request is only answered if the worker survived.
+        let dispatcher = rpc.start(1);
+        let output = SharedOutput::default();
+        let input = concat!(
+            r#"{"id":1,"method":"fail","msg":null}"#,
+            "\n",
+            r#"{"id":2,"method":"echo","msg":"hello"}"#,
+            "\n"
+        );
+        dispatcher.serve(input.as_bytes(), output.clone());
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let messages: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["method"], "hello");
+        assert_eq!(messages[1]["id"], -1);
+        assert_eq!(messages[1]["err"]["code"], "internal");
+        assert_eq!(
+            messages[1]["err"]["message"],
+            "Request handler panicked: boom"
+        );
+        assert_eq!(messages[2]["id"], -2);
+        assert_eq!(messages[2]["msg"], "hello");
+    }
+
+    #[test]
+    fn loopback_addresses() {
+        assert!(ensure_loopback("127.0.0.1:9000").is_ok());
+        assert!(ensure_loopback("[::1]:9000").is_ok());
+        let err = ensure_loopback("0.0.0.0:9000").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(ensure_loopback("192.168.1.1:9000").is_err());
     }
 }
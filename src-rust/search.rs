@@ -1,45 +1,184 @@
+use crate::aggregation::{AggregationCollector, AggregationResult, Aggregations};
+use crate::collector::{Cursor, HitAddress, Interrupt, SearchAfter};
+use crate::document::{flat_doc_to_json, to_named_doc};
+use crate::error::{ErrorCode, RpcError};
+use crate::facet::{drill_down, FacetCollection, FacetOutput, FacetRequest};
+use crate::filter::{apply_filters, Filter};
+use crate::handles::elapsed_ms;
+use crate::index::text_fields;
+use crate::snippet::{HighlightOptions, SnippetOutput, Snippets};
+use crate::sort::{Sort, SortBy, SortOrder};
 use log::*;
-use tantivy::collector::{FacetCollector, MultiCollector, TopDocs};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::time::Instant;
+use tantivy::collector::{Count, MultiCollector, TopDocs};
 use tantivy::query::{AllQuery, QueryParser};
 use tantivy::schema::*;
-use tantivy::{Index, IndexReader};
-use toshi_types::{CreateQuery, Error, FlatNamedDocument, KeyValue, Query, ScoredDoc, Search};
+use tantivy::{DocAddress, Index, IndexReader, Searcher};
+use toshi_types::{CreateQuery, KeyValue, Query, Search};
 
-pub type SearchResults = toshi_types::SearchResults<FlatNamedDocument>;
+/// Search results in the format of Toshi, with a cursor for each hit.
+///
+/// `hits` is the number of returned documents, as in Toshi, and `total_hits`
+/// the number of all documents that match the query.
+#[derive(Serialize, Debug)]
+pub struct SearchResults {
+    pub hits: usize,
+    pub total_hits: usize,
+    pub docs: Vec<SearchHit>,
+    pub facets: Vec<KeyValue<String, u64>>,
+    /// Counts of the `facets` option, for each field and path.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub facet_counts: Vec<FacetOutput>,
+    /// Results of the `aggregations` option, by name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggregations: BTreeMap<String, AggregationResult>,
+    pub elapsed_ms: f64,
+}
+
+/// Options of a search request in the JSON format of Toshi.
+///
+/// These are the options of `QueryOptions` that apply to such requests. The others concern
+/// the parsing of string queries, which Toshi requests don't have.
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchOptions {
+    /// Number of hits to skip.
+    pub offset: Option<u32>,
+    /// Return the hits after this cursor, taken from a hit of a previous response.
+    pub cursor: Option<String>,
+    /// Stored fields to return. An empty list returns only scores and doc addresses.
+    /// Default: all stored fields.
+    pub fields: Option<Vec<String>>,
+    /// Fast fields to sort the hits by, instead of by score.
+    #[serde(default)]
+    pub sort: Vec<SortBy>,
+    /// Constraints on the hits that don't change their scores.
+    #[serde(default)]
+    pub filter: Vec<Filter>,
+    /// Fail with a timeout error if the search takes longer.
+    pub timeout_ms: Option<u64>,
+}
+
+/// What to compute for the hits of a search, in addition to the Toshi search request.
+#[derive(Deserialize, Debug, Default)]
+pub struct ResultOptions {
+    /// Snippets to create for the hits
+    #[serde(default)]
+    pub highlight: HighlightOptions,
+    /// Facet counts to collect, in addition to the facets of the search
+    #[serde(default)]
+    pub facets: Vec<FacetRequest>,
+    /// Aggregations over fast fields, by name
+    #[serde(default)]
+    pub aggregations: Aggregations,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub score: Option<f32>,
+    pub doc: BTreeMap<String, JsonValue>,
+    /// Snippets of the fields in the `highlight` options, by field name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub snippets: BTreeMap<String, SnippetOutput>,
+    pub address: HitAddress,
+    /// Pass as cursor to get the hits after this one.
+    pub cursor: String,
+}
+
+impl SearchResults {
+    fn new(
+        total_hits: usize,
+        docs: Vec<SearchHit>,
+        facets: Vec<KeyValue<String, u64>>,
+        facet_counts: Vec<FacetOutput>,
+        aggregations: BTreeMap<String, AggregationResult>,
+        start: Instant,
+    ) -> Self {
+        SearchResults {
+            hits: docs.len(),
+            total_hits,
+            docs,
+            facets,
+            facet_counts,
+            aggregations,
+            elapsed_ms: elapsed_ms(start),
+        }
+    }
+}
 
 pub fn search_index(
     index: &Index,
     reader: &IndexReader,
     search: Search,
-) -> Result<SearchResults, Error> {
+    options: &SearchOptions,
+    result_options: &ResultOptions,
+    interrupt: &Interrupt,
+) -> anyhow::Result<SearchResults> {
+    let start = Instant::now();
     let searcher = reader.searcher();
     let schema = index.schema();
     let mut multi_collector = MultiCollector::new();
+    let cursor = match &options.cursor {
+        Some(cursor) => Some(Cursor::decode(cursor)?),
+        None => None,
+    };
+    let (limit, offset) = (search.limit, options.offset.unwrap_or(0) as usize);
 
-    let sorted_top_handle = search.sort_by.clone().and_then(|sort_by| {
-        info!("Sorting with: {}", sort_by);
-        if let Some(f) = schema.get_field(&sort_by) {
-            let entry = schema.get_field_entry(f);
-            if entry.is_fast() && entry.is_stored() {
-                let c = TopDocs::with_limit(search.limit).order_by_u64_field(f);
-                return Some(multi_collector.add_collector(c));
-            }
+    // The `sort` option takes precedence over the descending sort of Toshi's `sort_by`.
+    let sort_by = match &search.sort_by {
+        Some(field) if options.sort.is_empty() => vec![SortBy {
+            field: field.clone(),
+            order: SortOrder::Desc,
+        }],
+        _ => options.sort.clone(),
+    };
+    info!("Sorting with: {:?}", sort_by);
+    let sort = Sort::new(&schema, &sort_by)?;
+
+    let (top_handle, sorted_top_handle) = if sort.is_empty() {
+        let top_docs = TopDocs::with_limit(limit).and_offset(offset);
+        let collector = SearchAfter::new(top_docs, cursor, None);
+        (Some(multi_collector.add_collector(collector)), None)
+    } else {
+        let top_docs = sort.top_docs(&searcher, limit, offset)?;
+        let collector = SearchAfter::new(top_docs, cursor, Some(sort));
+        (None, Some(multi_collector.add_collector(collector)))
+    };
+    let count_handle = multi_collector.add_collector(Count);
+    // The facets of the Toshi request are returned as flat list, as in Toshi.
+    let toshi_facets = match &search.facets {
+        Some(f) => {
+            let request = FacetRequest {
+                field: f.get_facets_fields().to_string(),
+                paths: f.get_facets_values(),
+                top_k: None,
+                drill_down: None,
+            };
+            let collection = FacetCollection::new(&schema, &request)?;
+            Some((
+                multi_collector.add_collector(collection.collector()),
+                collection,
+            ))
         }
+        None => None,
+    };
+    let mut facet_handles = vec![];
+    for request in &result_options.facets {
+        let collection = FacetCollection::new(&schema, request)?;
+        facet_handles.push((
+            multi_collector.add_collector(collection.collector()),
+            collection,
+        ));
+    }
+
+    let aggregation_handle = if result_options.aggregations.is_empty() {
         None
-    });
-
-    let top_handle = multi_collector.add_collector(TopDocs::with_limit(search.limit));
-    let facet_handle = search.facets.clone().and_then(|f| {
-        if let Some(field) = schema.get_field(f.get_facets_fields()) {
-            let mut col = FacetCollector::for_field(field);
-            for term in f.get_facets_values() {
-                col.add_facet(&term);
-            }
-            Some(multi_collector.add_collector(col))
-        } else {
-            None
-        }
-    });
+    } else {
+        let collector = AggregationCollector::new(&searcher, &result_options.aggregations)?;
+        Some(multi_collector.add_collector(collector))
+    };
 
     if let Some(query) = search.query {
         let gen_query = match query {
@@ -50,54 +189,104 @@ pub fn search_index(
             Query::Range(range) => range.create_query(&schema)?,
             Query::Boolean { bool } => bool.create_query(&schema)?,
             Query::Raw { raw } => {
-                let fields: Vec<Field> = schema
-                    .fields()
-                    .filter_map(|f| schema.get_field(f.1.name()))
-                    .collect();
-                let query_parser = QueryParser::for_index(&index, fields);
-                query_parser.parse_query(&raw)?
+                let query_parser = QueryParser::for_index(index, text_fields(&schema));
+                query_parser
+                    .parse_query(&raw)
+                    .map_err(|err| RpcError::query_parse_error(&raw, &err))?
             }
             Query::All => Box::new(AllQuery),
         };
+        // Snippets are created for the query without the filters and drill-downs.
+        let filtered_query = drill_down(&schema, &result_options.facets, gen_query.box_clone())?;
+        let filtered_query = apply_filters(&schema, filtered_query, &options.filter)?;
 
         trace!("{:?}", gen_query);
-        let mut scored_docs = searcher.search(&*gen_query, &multi_collector)?;
+        let collector = interrupt.wrap(multi_collector);
+        let mut scored_docs = searcher.search(&*filtered_query, &collector)?;
+
+        let snippets = Snippets::new(
+            &searcher,
+            &*gen_query,
+            &schema,
+            None,
+            &result_options.highlight,
+        )?;
+        let fields = options.fields.as_deref();
 
         // FruitHandle isn't a public type which leads to some duplicate code like this.
-        let docs: Vec<ScoredDoc<FlatNamedDocument>> = if let Some(h) = sorted_top_handle {
-            h.extract(&mut scored_docs)
-                .into_iter()
-                .map(|(score, doc)| {
-                    let d = searcher.doc(doc).expect("Doc not found in segment");
-                    ScoredDoc::<FlatNamedDocument>::new(
-                        Some(score as f32),
-                        schema.to_named_doc(&d).into(),
-                    )
-                })
-                .collect()
-        } else {
-            top_handle
-                .extract(&mut scored_docs)
-                .into_iter()
-                .map(|(score, doc)| {
-                    let d = searcher.doc(doc).expect("Doc not found in segment");
-                    ScoredDoc::<FlatNamedDocument>::new(Some(score), schema.to_named_doc(&d).into())
-                })
-                .collect()
-        };
+        let mut docs = vec![];
+        if let Some(h) = sorted_top_handle {
+            for (value, address) in h.extract(&mut scored_docs) {
+                let score = value.score;
+                let cursor = Cursor {
+                    values: value.values,
+                    address,
+                };
+                docs.push(search_hit(
+                    &searcher, &schema, &snippets, fields, score, address, cursor,
+                )?);
+            }
+        }
+        if let Some(h) = top_handle {
+            for (score, address) in h.extract(&mut scored_docs) {
+                let cursor = Cursor::from_score(score, address);
+                docs.push(search_hit(
+                    &searcher, &schema, &snippets, fields, score, address, cursor,
+                )?);
+            }
+        }
+
+        let total_hits = count_handle.extract(&mut scored_docs);
 
-        if let Some(facets) = facet_handle {
-            if let Some(t) = &search.facets {
-                let facet_counts = facets
-                    .extract(&mut scored_docs)
-                    .get(&t.get_facets_values()[0])
-                    .map(|(f, c)| KeyValue::new(f.to_string(), c))
-                    .collect();
-                return Ok(SearchResults::with_facets(docs, facet_counts));
+        let mut toshi_facet_counts = vec![];
+        if let Some((handle, collection)) = toshi_facets {
+            let counts = handle.extract(&mut scored_docs);
+            for output in collection.outputs(&counts) {
+                toshi_facet_counts.extend(output.counts);
             }
         }
-        Ok(SearchResults::new(docs))
+        let mut facet_counts = vec![];
+        for (handle, collection) in facet_handles {
+            let counts = handle.extract(&mut scored_docs);
+            facet_counts.extend(collection.outputs(&counts));
+        }
+        let aggregations = match aggregation_handle {
+            Some(handle) => handle.extract(&mut scored_docs).results(),
+            None => BTreeMap::new(),
+        };
+        Ok(SearchResults::new(
+            total_hits,
+            docs,
+            toshi_facet_counts,
+            facet_counts,
+            aggregations,
+            start,
+        ))
     } else {
-        Err(Error::QueryError("Empty Query Provided".into()))
+        Err(RpcError::new(ErrorCode::InvalidArgument, "Empty Query Provided").into())
     }
 }
+
+fn search_hit(
+    searcher: &Searcher,
+    schema: &Schema,
+    snippets: &Snippets,
+    fields: Option<&[String]>,
+    score: f32,
+    address: DocAddress,
+    cursor: Cursor,
+) -> anyhow::Result<SearchHit> {
+    let load_doc = !matches!(fields, Some(fields) if fields.is_empty()) || !snippets.is_empty();
+    let doc = if load_doc {
+        searcher.doc(address)?
+    } else {
+        Document::default()
+    };
+    Ok(SearchHit {
+        score: Some(score),
+        doc: flat_doc_to_json(to_named_doc(schema, &doc, fields)),
+        snippets: snippets.snippets(&doc),
+        address: address.into(),
+        cursor: cursor.encode(),
+    })
+}
@@ -0,0 +1,261 @@
+use crate::options::QueryOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tantivy::query::Query;
+use tantivy::schema::{Document, FieldType, Schema};
+use tantivy::{Result, Searcher, Snippet, SnippetGenerator, TantivyError};
+
+/// How snippets are returned to clients.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SnippetFormat {
+    /// HTML with the highlighted keywords wrapped in tags.
+    #[default]
+    Html,
+    /// The raw text fragment and the byte ranges of the highlighted keywords.
+    Ranges,
+}
+
+/// Which snippets to create for the hits of a query, and how.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HighlightOptions {
+    /// Fields to create snippets for.
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Max length of the snippets (default 150 characters).
+    pub max_chars: Option<usize>,
+    #[serde(default)]
+    pub format: SnippetFormat,
+    /// Start and end tag to wrap highlighted keywords in HTML snippets (default `<b>`, `</b>`).
+    pub tags: Option<(String, String)>,
+}
+
+impl QueryOptions {
+    pub fn highlight_options(&self) -> HighlightOptions {
+        HighlightOptions {
+            fields: self.snippet_fields.clone(),
+            max_chars: self.snippet_max_chars,
+            format: self.snippet_format,
+            tags: self.snippet_tags.clone(),
+        }
+    }
+}
+
+/// A snippet as sent to clients.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SnippetOutput {
+    Html(String),
+    Ranges {
+        fragment: String,
+        highlighted: Vec<(usize, usize)>,
+    },
+}
+
+/// Snippet generators for the snippet fields of a query.
+pub struct Snippets {
+    /// Generator for the legacy `snippet_field`, always rendered as HTML.
+    snippet_field: Option<SnippetGenerator>,
+    fields: Vec<(String, SnippetGenerator)>,
+    format: SnippetFormat,
+    tags: Option<(String, String)>,
+}
+
+impl Snippets {
+    /// Create the snippet generators. The snippet fields have to be stored text fields.
+    ///
+    /// `snippet_field` is the field of the single HTML snippet of the string query API.
+    /// For compatibility, it is ignored if it is not in the schema.
+    pub fn new(
+        searcher: &Searcher,
+        query: &dyn Query,
+        schema: &Schema,
+        snippet_field: Option<&str>,
+        options: &HighlightOptions,
+    ) -> Result<Snippets> {
+        let generator = |field_name: &str| -> Result<Option<SnippetGenerator>> {
+            let field = match schema.get_field(field_name) {
+                Some(field) => field,
+                None => return Ok(None),
+            };
+            let mut generator = SnippetGenerator::create(searcher, query, field)?;
+            if let Some(max_chars) = options.max_chars {
+                generator.set_max_num_chars(max_chars);
+            }
+            Ok(Some(generator))
+        };
+        let snippet_field = match snippet_field {
+            Some(field_name) => generator(field_name)?,
+            None => None,
+        };
+        let mut fields = vec![];
+        for field_name in &options.fields {
+            check_snippet_field(schema, field_name)?;
+            if let Some(generator) = generator(field_name)? {
+                fields.push((field_name.clone(), generator));
+            }
+        }
+        Ok(Snippets {
+            snippet_field,
+            fields,
+            format: options.format,
+            tags: options.tags.clone(),
+        })
+    }
+
+    /// True if no snippets are created, so that the documents are not needed.
+    pub fn is_empty(&self) -> bool {
+        self.snippet_field.is_none() && self.fields.is_empty()
+    }
+
+    /// The HTML snippet of the `snippet_field`.
+    pub fn snippet(&self, doc: &Document) -> Option<String> {
+        let generator = self.snippet_field.as_ref()?;
+        Some(self.to_html(&generator.snippet_from_doc(doc)))
+    }
+
+    /// The snippets of the `snippet_fields`, by field name.
+    pub fn snippets(&self, doc: &Document) -> BTreeMap<String, SnippetOutput> {
+        let mut snippets = BTreeMap::new();
+        for (field_name, generator) in &self.fields {
+            let snippet = generator.snippet_from_doc(doc);
+            let output = match self.format {
+                SnippetFormat::Html => SnippetOutput::Html(self.to_html(&snippet)),
+                SnippetFormat::Ranges => SnippetOutput::Ranges {
+                    fragment: snippet.fragment().to_string(),
+                    highlighted: snippet
+                        .highlighted()
+                        .iter()
+                        .map(|h| (h.start, h.end))
+                        .collect(),
+                },
+            };
+            snippets.insert(field_name.clone(), output);
+        }
+        snippets
+    }
+
+    fn to_html(&self, snippet: &Snippet) -> String {
+        let (start_tag, end_tag) = match &self.tags {
+            Some(tags) => tags,
+            None => return snippet.to_html(),
+        };
+        let fragment = snippet.fragment();
+        let mut html = String::new();
+        let mut position = 0;
+        for highlighted in snippet.highlighted() {
+            let (start, end) = (highlighted.start, highlighted.end);
+            html.push_str(&escape_html(&fragment[position..start]));
+            html.push_str(start_tag);
+            html.push_str(&escape_html(&fragment[start..end]));
+            html.push_str(end_tag);
+            position = end;
+        }
+        html.push_str(&escape_html(&fragment[position..]));
+        html
+    }
+}
+
+/// Snippets are created from the stored text of a field.
+fn check_snippet_field(schema: &Schema, field_name: &str) -> Result<()> {
+    let field = schema.get_field(field_name).ok_or_else(|| {
+        TantivyError::InvalidArgument(format!("Snippet field not found: {}", field_name))
+    })?;
+    let entry = schema.get_field_entry(field);
+    if !matches!(entry.field_type(), FieldType::Str(_)) || !entry.is_stored() {
+        return Err(TantivyError::InvalidArgument(format!(
+            "Snippet field is not a stored text field: {}",
+            field_name
+        )));
+    }
+    Ok(())
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::Interrupt;
+    use crate::fixture::Fixture;
+    use crate::options::QueryOptions;
+    use tantivy::schema::{STORED, TEXT};
+
+    #[test]
+    fn query_snippets() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("title", TEXT | STORED);
+        schema_builder.add_text_field("body", TEXT | STORED);
+        schema_builder.add_text_field("tag", TEXT);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([
+            { "title": "hello world", "body": "say hello to <everyone>", "tag": "hello" }
+        ]));
+        let handle = &fixture.handle;
+
+        let options = QueryOptions {
+            snippet_field: Some("title".to_string()),
+            snippet_fields: vec!["title".to_string(), "body".to_string()],
+            snippet_format: SnippetFormat::Ranges,
+            ..Default::default()
+        };
+        let results = handle.query("hello", &options, &Interrupt::none()).unwrap();
+        let hit = &results.hits[0];
+        assert_eq!(hit.snippet.as_deref(), Some("<b>hello</b> world"));
+        match &hit.snippets["body"] {
+            SnippetOutput::Ranges {
+                fragment,
+                highlighted,
+            } => {
+                // Fragments end with the last token.
+                assert_eq!(fragment, "say hello to <everyone");
+                assert_eq!(highlighted, &vec![(4, 9)]);
+            }
+            output => panic!("Unexpected snippet: {:?}", output),
+        }
+
+        let options = QueryOptions {
+            snippet_fields: vec!["body".to_string()],
+            snippet_tags: Some(("<mark>".to_string(), "</mark>".to_string())),
+            ..Default::default()
+        };
+        let results = handle.query("hello", &options, &Interrupt::none()).unwrap();
+        match &results.hits[0].snippets["body"] {
+            SnippetOutput::Html(html) => {
+                assert_eq!(html, "say <mark>hello</mark> to &lt;everyone");
+            }
+            output => panic!("Unexpected snippet: {:?}", output),
+        }
+
+        for field_name in &["unknown", "tag"] {
+            let options = QueryOptions {
+                snippet_fields: vec![field_name.to_string()],
+                ..Default::default()
+            };
+            let err = handle
+                .query("hello", &options, &Interrupt::none())
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<TantivyError>(),
+                    Some(TantivyError::InvalidArgument(_))
+                ),
+                "{}",
+                field_name
+            );
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use crate::error::{ErrorCode, RpcError};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use tantivy::collector::{Collector, TopDocs};
+use tantivy::fastfield::{DynamicFastFieldReader, FastFieldReader};
+use tantivy::schema::{Cardinality, Field, FieldType, Schema};
+use tantivy::{DocAddress, DocId, Score, Searcher, SegmentReader, TantivyError};
+
+/// A field to sort the hits of a query by, as sent by clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SortBy {
+    pub field: String,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Sort
+///
+/// The fast fields to sort hits by, in order of precedence. Hits with equal values
+/// in all fields are ordered by doc address.
+///
+/// Fast fields store the values of all numeric and date types as u64 that sort
+/// in the same order as the values, so hits are sorted by these u64. Values of
+/// fields that are sorted ascending are inverted, so that all keys sort descending,
+/// like scores.
+#[derive(Debug, Clone, Default)]
+pub struct Sort {
+    fields: Vec<(Field, SortOrder)>,
+}
+
+impl Sort {
+    /// Check the sort fields against the schema. Only single-valued fast fields
+    /// of a numeric or date type can be sorted by.
+    pub fn new(schema: &Schema, sort_by: &[SortBy]) -> Result<Sort, RpcError> {
+        let mut fields = vec![];
+        for sort_by in sort_by {
+            let not_sortable = |message: &str| {
+                RpcError::new(ErrorCode::InvalidArgument, message)
+                    .with_details(serde_json::json!({ "field": sort_by.field }))
+            };
+            let field = schema
+                .get_field(&sort_by.field)
+                .ok_or_else(|| not_sortable("Sort field not found."))?;
+            let entry = schema.get_field_entry(field);
+            // The fast fields of multi-valued fields can be read as u64 as well, but they
+            // don't have the values of the documents.
+            let cardinality = match entry.field_type() {
+                FieldType::U64(options)
+                | FieldType::I64(options)
+                | FieldType::F64(options)
+                | FieldType::Date(options) => options.get_fastfield_cardinality(),
+                _ => None,
+            };
+            if cardinality != Some(Cardinality::SingleValue) {
+                return Err(not_sortable(
+                    "Only single-valued fast fields of numeric or date types can be sorted by.",
+                ));
+            }
+            fields.push((field, sort_by.order));
+        }
+        Ok(Sort { fields })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The sort keys of the documents of a segment.
+    pub fn segment_keys(&self, segment: &SegmentReader) -> tantivy::Result<SortKeys> {
+        let mut readers = vec![];
+        for (field, order) in &self.fields {
+            readers.push((segment.fast_fields().u64_lenient(*field)?, *order));
+        }
+        Ok(SortKeys { readers })
+    }
+
+    /// A `TopDocs` collector that orders hits by the sort fields.
+    ///
+    /// The fast fields are read for all segments of the searcher first, and the collector fails
+    /// with an invalid argument error if they can't be read in a segment.
+    pub fn top_docs(
+        &self,
+        searcher: &Searcher,
+        limit: usize,
+        offset: usize,
+    ) -> tantivy::Result<impl Collector<Fruit = Vec<(SortValue, DocAddress)>>> {
+        let mut segment_keys = HashMap::new();
+        for segment in searcher.segment_readers() {
+            let keys = self.segment_keys(segment).map_err(|err| {
+                TantivyError::InvalidArgument(format!("Cannot read the sort fields: {}", err))
+            })?;
+            segment_keys.insert(segment.segment_id(), keys);
+        }
+        let collector = TopDocs::with_limit(limit).and_offset(offset).tweak_score(
+            move |segment: &SegmentReader| {
+                // Only the segments of the searcher are collected, so the keys are always found.
+                let keys = segment_keys
+                    .get(&segment.segment_id())
+                    .cloned()
+                    .unwrap_or_default();
+                move |doc: DocId, score: Score| SortValue {
+                    values: keys.key(doc),
+                    score,
+                }
+            },
+        );
+        Ok(collector)
+    }
+}
+
+/// The fast field readers of the sort fields in a segment.
+#[derive(Clone, Default)]
+pub struct SortKeys {
+    readers: Vec<(DynamicFastFieldReader<u64>, SortOrder)>,
+}
+
+impl SortKeys {
+    /// The sort key of a document. Greater keys come first.
+    pub fn key(&self, doc: DocId) -> Vec<u64> {
+        self.readers
+            .iter()
+            .map(|(reader, order)| match order {
+                SortOrder::Desc => reader.get(doc),
+                SortOrder::Asc => !reader.get(doc),
+            })
+            .collect()
+    }
+}
+
+/// The sort key of a hit, together with its score. Only the key is compared.
+#[derive(Debug, Clone)]
+pub struct SortValue {
+    pub values: Vec<u64>,
+    pub score: Score,
+}
+
+impl PartialEq for SortValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values
+    }
+}
+
+impl PartialOrd for SortValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.values.cmp(&other.values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::Interrupt;
+    use crate::fixture::{ids, Fixture};
+    use crate::options::QueryOptions;
+    use tantivy::schema::{
+        Cardinality, NumericOptions, Schema, FAST, INDEXED, STORED, STRING, TEXT,
+    };
+
+    #[test]
+    fn query_sorted() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("id", STRING | STORED);
+        schema_builder.add_text_field("body", TEXT);
+        schema_builder.add_i64_field("rank", INDEXED | FAST);
+        schema_builder.add_f64_field("price", FAST);
+        schema_builder.add_u64_field("size", INDEXED | STORED);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([
+            { "id": "a", "body": "x", "rank": -5, "price": 2.5, "size": 1 },
+            { "id": "b", "body": "x", "rank": 10, "price": 1.5, "size": 1 },
+            { "id": "c", "body": "x", "rank": -5, "price": -1.0, "size": 1 },
+            { "id": "d", "body": "x", "rank": 0, "price": 0.0, "size": 1 },
+        ]));
+        let handle = &fixture.handle;
+
+        let sort = serde_json::from_value(serde_json::json!([
+            { "field": "rank" },
+            { "field": "price", "order": "desc" },
+        ]))
+        .unwrap();
+        let options = QueryOptions {
+            sort,
+            ..Default::default()
+        };
+        let results = handle.query("x", &options, &Interrupt::none()).unwrap();
+        assert_eq!(ids(&results), vec!["a", "c", "d", "b"]);
+        assert!(results.hits[0].score > 0.0);
+
+        // Continue after the second hit.
+        let options = QueryOptions {
+            limit: Some(1),
+            cursor: Some(results.hits[1].cursor.clone()),
+            ..options
+        };
+        let results = handle.query("x", &options, &Interrupt::none()).unwrap();
+        assert_eq!(ids(&results), vec!["d"]);
+
+        for field in &["size", "body", "missing"] {
+            let options = QueryOptions {
+                sort: vec![SortBy {
+                    field: field.to_string(),
+                    order: SortOrder::Desc,
+                }],
+                ..Default::default()
+            };
+            assert!(handle.query("x", &options, &Interrupt::none()).is_err());
+        }
+    }
+
+    #[test]
+    fn sort_multi_valued() {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("body", TEXT);
+        let options = NumericOptions::default().set_fast(Cardinality::MultiValues);
+        schema_builder.add_u64_field("tags", options);
+        let fixture = Fixture::new(schema_builder.build());
+        fixture.add(serde_json::json!([{ "body": "x", "tags": [1, 2] }]));
+        let options = QueryOptions {
+            sort: vec![SortBy {
+                field: "tags".to_string(),
+                order: SortOrder::Desc,
+            }],
+            ..Default::default()
+        };
+        let err = fixture
+            .handle
+            .query("x", &options, &Interrupt::none())
+            .unwrap_err();
+        assert_eq!(RpcError::from(err).code, ErrorCode::InvalidArgument);
+    }
+}
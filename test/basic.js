@@ -36,15 +36,16 @@ test('basic indexing and query', async t => {
     await index.add(docs)
 
     let results = await index.query('mundo')
-    t.equal(results.length, 1)
-    let first = results[0]
+    t.equal(results.total_hits, 1)
+    t.equal(results.hits.length, 1)
+    let first = results.hits[0]
     t.equal(first.doc.id[0], 'first2')
     t.equal(first.snippet, null)
     // console.log(results[0])
 
     results = await index.query('more', { snippetField: 'body' })
-    t.equal(results.length, 1)
-    first = results[0]
+    t.equal(results.hits.length, 1)
+    first = results.hits[0]
     t.equal(first.doc.id[0], 'first1')
     t.equal(first.snippet, 'hi first tell me <b>more</b>')
     // console.log(results[0])
@@ -79,6 +80,7 @@ test('basic indexing and query', async t => {
     let results = await index.queryJson(query)
     console.log('RESULTS 1', typeof results, results)
     t.equal(results.docs.length, 1, 'one result')
+    t.equal(results.total_hits, 1, 'one total hit')
     query.query.bool.must_not[0].term.title = 'foo'
     console.log('QUERY', query.query)
     results = await index.queryJson(query)
@@ -90,4 +92,46 @@ test('basic indexing and query', async t => {
   } catch (err) {
     t.fail(err)
   }
-})
\ No newline at end of file
+})
+
+test('reject invalid documents', async t => {
+  const [dir, cleanup] = await tempdir()
+  const catalog = new Sonar(dir)
+  const index = await catalog.openOrCreate('index-name', getSchema())
+  const docs = [...getDocs(), { id: 'third', title: 'Hi', stray: 'field' }]
+  try {
+    await index.add(docs)
+    t.fail('invalid documents were not rejected')
+  } catch (err) {
+    t.equal(err.code, 'invalid_argument')
+    t.equal(err.details.length, 1)
+    t.equal(err.details[0].document, 2)
+    t.equal(err.details[0].kind, 'unknown_field')
+  }
+  t.equal((await index.query('hi')).total_hits, 0, 'no documents added')
+
+  const { added, errors } = await index.add(docs, { onError: 'skip' })
+  t.equal(added, 2)
+  t.equal(errors.length, 1)
+  await catalog.close()
+  await cleanup()
+  t.end()
+})
+
+test('delete documents without commit', async t => {
+  const [dir, cleanup] = await tempdir()
+  const catalog = new Sonar(dir)
+  const schema = getSchema().map(field => {
+    if (field.name !== 'id') return field
+    return { ...field, options: { indexing: { record: 'basic', tokenizer: 'raw' }, stored: true } }
+  })
+  const index = await catalog.openOrCreate('index-name', schema)
+  await index.add(getDocs())
+  await index.deleteDocuments('id', ['first1'], { commit: false })
+  t.equal((await index.query('hi')).total_hits, 2, 'deletion not committed yet')
+  await index.commit()
+  t.equal((await index.query('hi')).total_hits, 1, 'deletion committed')
+  await catalog.close()
+  await cleanup()
+  t.end()
+})